@@ -1,8 +1,20 @@
 fn main() {
+  // Migrations are embedded with `sqlx::migrate!`
+  println!("cargo:rerun-if-changed=migrations");
+
   tonic_build::configure()
     .build_server(true)
     .type_attribute("ItemDescriptor", "#[derive(::serde::Deserialize)]")
     .type_attribute("ItemModifier", "#[derive(::serde::Deserialize)]")
+    .type_attribute("EquipmentStats", "#[derive(::serde::Deserialize)]")
+    .field_attribute(
+      "EquipmentStats.slot",
+      "#[serde(deserialize_with = \"crate::game::equipment::deserialize_slot\")]",
+    )
+    .type_attribute(
+      "LootBonuses",
+      "#[derive(::serde::Deserialize)] #[serde(default)]",
+    )
     .type_attribute("LocationDescriptor", "#[derive(::serde::Deserialize)]")
     .compile(&["protos/mine.proto"], &["protos/"])
     .expect("Failed to compile protos with `prost-build`");
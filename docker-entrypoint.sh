@@ -0,0 +1,6 @@
+#!/bin/sh
+set -e
+
+# Apply any pending migrations with the same config the server is started with, then start it
+"$@" --migrate-only
+exec "$@"
@@ -0,0 +1,3 @@
+-- Usernames are unique case-insensitively.  This will fail if there are existing users whose names
+-- differ only by case; those need to be renamed by hand first.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_username_lower_unique ON users (lower(username));
@@ -0,0 +1,2 @@
+ALTER TABLE users ALTER COLUMN hashed_password SET NOT NULL;
+ALTER TABLE users DROP COLUMN IF EXISTS is_guest;
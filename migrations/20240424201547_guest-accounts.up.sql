@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_guest boolean NOT NULL DEFAULT false;
+-- Guests don't have a password until they claim their account
+ALTER TABLE users ALTER COLUMN hashed_password DROP NOT NULL;
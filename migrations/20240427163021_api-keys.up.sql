@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS api_keys (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users(id),
+  name text NOT NULL,
+  -- SHA-256 of the full key; the key itself is never stored
+  key_hash text NOT NULL UNIQUE,
+  key_prefix text NOT NULL,
+  scopes text[] NOT NULL,
+  created_at timestamp NOT NULL DEFAULT now(),
+  last_used_at timestamp,
+  revoked_at timestamp
+);
+CREATE INDEX IF NOT EXISTS idx_api_keys_user_id ON api_keys(user_id);
@@ -0,0 +1,3 @@
+ALTER TABLE items DROP COLUMN IF EXISTS removed_at;
+ALTER TABLE items DROP COLUMN IF EXISTS image_url;
+ALTER TABLE items DROP COLUMN IF EXISTS display_name;
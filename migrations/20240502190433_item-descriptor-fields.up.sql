@@ -0,0 +1,4 @@
+ALTER TABLE items ADD COLUMN IF NOT EXISTS display_name text NOT NULL DEFAULT '';
+ALTER TABLE items ADD COLUMN IF NOT EXISTS image_url text;
+-- Set for items that were removed from `loot.yml` but are still referenced by someone's inventory
+ALTER TABLE items ADD COLUMN IF NOT EXISTS removed_at timestamp;
@@ -0,0 +1,18 @@
+ALTER TABLE api_keys DROP CONSTRAINT api_keys_user_id_fkey,
+  ADD CONSTRAINT api_keys_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id);
+ALTER TABLE bases DROP CONSTRAINT bases_user_id_fkey,
+  ADD CONSTRAINT bases_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id);
+ALTER TABLE inventory DROP CONSTRAINT inventory_user_id_fkey,
+  ADD CONSTRAINT inventory_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id);
+ALTER TABLE sessions DROP CONSTRAINT sessions_user_id_fkey,
+  ADD CONSTRAINT sessions_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id);
+
+ALTER TABLE bases DROP CONSTRAINT bases_pkey;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_user_id_unique_storage_level ON bases(user_id);
+
+CREATE INDEX IF NOT EXISTS token_index ON sessions(token);
+DROP INDEX IF EXISTS idx_sessions_user_id;
+
+DROP INDEX IF EXISTS idx_inventory_user_id_item_id;
+DROP INDEX IF EXISTS idx_inventory_user_id_value;
+DROP INDEX IF EXISTS idx_inventory_user_id_created_at;
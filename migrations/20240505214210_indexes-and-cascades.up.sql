@@ -0,0 +1,23 @@
+-- Inventory lookups are always scoped to a single user.  `(user_id, created_at)` serves the default
+-- inventory sort and user-wide counts, `(user_id, value)` serves the value sort and lets hiscores sum
+-- values with an index-only scan, and `(user_id, item_id)` serves the aggregated inventory and debits.
+CREATE INDEX IF NOT EXISTS idx_inventory_user_id_created_at ON inventory (user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_inventory_user_id_value ON inventory (user_id, value);
+CREATE INDEX IF NOT EXISTS idx_inventory_user_id_item_id ON inventory (user_id, item_id) INCLUDE (quality, value);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user_id ON sessions (user_id);
+-- Redundant with the unique constraint on `token`
+DROP INDEX IF EXISTS token_index;
+
+ALTER TABLE bases ADD CONSTRAINT bases_pkey PRIMARY KEY USING INDEX idx_user_id_unique_storage_level;
+
+-- Everything belonging to a user goes away with them.  Items can't be deleted while referenced;
+-- they're tombstoned instead.
+ALTER TABLE sessions DROP CONSTRAINT sessions_user_id_fkey,
+  ADD CONSTRAINT sessions_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE inventory DROP CONSTRAINT inventory_user_id_fkey,
+  ADD CONSTRAINT inventory_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE bases DROP CONSTRAINT bases_user_id_fkey,
+  ADD CONSTRAINT bases_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE api_keys DROP CONSTRAINT api_keys_user_id_fkey,
+  ADD CONSTRAINT api_keys_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
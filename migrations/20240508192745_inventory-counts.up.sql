@@ -0,0 +1,10 @@
+-- Number of rows each user has in `inventory`, so capacity checks don't have to count them.  Kept
+-- up to date by the server in the same transaction as every insert and delete.
+CREATE TABLE IF NOT EXISTS inventory_counts (
+  user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  item_count INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO inventory_counts (user_id, item_count)
+SELECT user_id, COUNT(*) FROM inventory GROUP BY user_id
+ON CONFLICT (user_id) DO UPDATE SET item_count = EXCLUDED.item_count;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS inventory_stacks;
+ALTER TABLE items DROP COLUMN IF EXISTS stack_size;
@@ -0,0 +1,16 @@
+ALTER TABLE items ADD COLUMN IF NOT EXISTS stack_size integer;
+
+-- Stackable items are stored as one stack per user per item rather than one `inventory` row each.
+-- Individual qualities aren't kept; `quality_histogram` holds the number of items in each of a
+-- fixed number of equal-width quality buckets.
+CREATE TABLE IF NOT EXISTS inventory_stacks (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  item_id integer NOT NULL REFERENCES items(id),
+  count integer NOT NULL,
+  total_quality float8 NOT NULL,
+  total_value float8 NOT NULL,
+  quality_histogram integer[] NOT NULL,
+  created_at timestamp NOT NULL DEFAULT now(),
+  UNIQUE (user_id, item_id)
+);
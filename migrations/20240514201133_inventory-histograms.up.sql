@@ -0,0 +1,36 @@
+-- Totals and quality histogram of each item in each user's inventory, across both individual items
+-- and stacks.  Kept up to date by the server whenever the inventory changes.
+CREATE TABLE IF NOT EXISTS inventory_histograms (
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  item_id integer NOT NULL REFERENCES items(id) ON DELETE CASCADE,
+  total_count integer NOT NULL,
+  total_quality float8 NOT NULL,
+  total_value float8 NOT NULL,
+  quality_histogram integer[] NOT NULL,
+  PRIMARY KEY (user_id, item_id)
+);
+
+-- Same as `rebuild_quality_histograms` with the default of 32 buckets
+WITH entries (user_id, item_id, bucket_ix, count, quality, value) AS (
+  SELECT user_id, item_id, LEAST(FLOOR(quality * 32)::int, 31), 1, quality::float8, value::float8
+  FROM inventory
+  UNION ALL
+  SELECT stack.user_id, stack.item_id, (bucket.ix - 1)::int, bucket.count, 0, 0
+  FROM inventory_stacks stack, UNNEST(stack.quality_histogram) WITH ORDINALITY AS bucket(count, ix)
+  UNION ALL
+  SELECT user_id, item_id, 0, 0, total_quality, total_value FROM inventory_stacks
+), buckets AS (
+  SELECT user_id, item_id, bucket_ix, SUM(count) AS count, SUM(quality) AS quality,
+    SUM(value) AS value
+  FROM entries GROUP BY user_id, item_id, bucket_ix
+)
+INSERT INTO inventory_histograms (user_id, item_id, total_count, total_quality, total_value,
+  quality_histogram)
+SELECT item.user_id, item.item_id, COALESCE(SUM(buckets.count), 0),
+  COALESCE(SUM(buckets.quality), 0), COALESCE(SUM(buckets.value), 0),
+  array_agg(COALESCE(buckets.count, 0)::int ORDER BY ix.bucket_ix)
+FROM (SELECT DISTINCT user_id, item_id FROM buckets) item
+CROSS JOIN generate_series(0, 31) AS ix(bucket_ix)
+LEFT JOIN buckets ON buckets.user_id = item.user_id AND buckets.item_id = item.item_id
+  AND buckets.bucket_ix = ix.bucket_ix
+GROUP BY item.user_id, item.item_id;
@@ -0,0 +1,2 @@
+ALTER TABLE inventory_stacks DROP COLUMN IF EXISTS locked;
+ALTER TABLE inventory DROP COLUMN IF EXISTS locked;
@@ -0,0 +1,3 @@
+-- Locked items and stacks are never consumed by debits
+ALTER TABLE inventory ADD COLUMN IF NOT EXISTS locked boolean NOT NULL DEFAULT false;
+ALTER TABLE inventory_stacks ADD COLUMN IF NOT EXISTS locked boolean NOT NULL DEFAULT false;
@@ -0,0 +1,18 @@
+-- Append-only record of every change to every user's inventory.  Rows are never updated or
+-- deleted by the server.  `item_id` has no foreign key so that history outlives removed items.
+CREATE TABLE IF NOT EXISTS inventory_ledger (
+  id bigserial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  item_id integer NOT NULL,
+  -- The individual item or stack that changed
+  item_uuid uuid NOT NULL,
+  count_change integer NOT NULL,
+  quality_change float8 NOT NULL,
+  value_change float8 NOT NULL,
+  reason text NOT NULL,
+  reason_detail text,
+  created_at timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_inventory_ledger_user_id_id ON inventory_ledger (user_id, id);
+CREATE INDEX IF NOT EXISTS idx_inventory_ledger_item_uuid ON inventory_ledger (item_uuid);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS user_best_finds;
+DROP TABLE IF EXISTS user_location_stats;
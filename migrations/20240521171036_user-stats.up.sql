@@ -0,0 +1,50 @@
+-- Lifetime mining stats for each user at each location.  Unlike the inventory, these never go down
+-- when items are spent.
+CREATE TABLE IF NOT EXISTS user_location_stats (
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  location_name text NOT NULL,
+  total_ticks bigint NOT NULL DEFAULT 0,
+  mining_millis bigint NOT NULL DEFAULT 0,
+  total_items bigint NOT NULL DEFAULT 0,
+  total_value float8 NOT NULL DEFAULT 0,
+  PRIMARY KEY (user_id, location_name)
+);
+
+-- The best item each user has ever found, by each of a few measures (`kind`), even if it's since
+-- been spent
+CREATE TABLE IF NOT EXISTS user_best_finds (
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  kind text NOT NULL,
+  item_id integer NOT NULL,
+  item_uuid uuid NOT NULL,
+  rarity_tier integer NOT NULL,
+  quality real NOT NULL,
+  value real NOT NULL,
+  location_name text NOT NULL,
+  found_at timestamp NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, kind)
+);
+
+-- Backfill what we can from the ledger.  Ticks and time spent mining weren't recorded before this.
+INSERT INTO user_location_stats (user_id, location_name, total_items, total_value)
+SELECT user_id, reason_detail, SUM(count_change), SUM(value_change)
+FROM inventory_ledger WHERE reason = 'Mined'
+GROUP BY user_id, reason_detail;
+
+INSERT INTO user_best_finds (user_id, kind, item_id, item_uuid, rarity_tier, quality, value,
+  location_name, found_at)
+SELECT DISTINCT ON (ledger.user_id) ledger.user_id, 'Rarest', ledger.item_id, ledger.item_uuid,
+  items.rarity_tier, ledger.quality_change, ledger.value_change, ledger.reason_detail,
+  ledger.created_at
+FROM inventory_ledger ledger JOIN items ON items.id = ledger.item_id
+WHERE ledger.reason = 'Mined'
+ORDER BY ledger.user_id, items.rarity_tier DESC, ledger.quality_change DESC;
+
+INSERT INTO user_best_finds (user_id, kind, item_id, item_uuid, rarity_tier, quality, value,
+  location_name, found_at)
+SELECT DISTINCT ON (ledger.user_id) ledger.user_id, 'MostValuable', ledger.item_id,
+  ledger.item_uuid, items.rarity_tier, ledger.quality_change, ledger.value_change,
+  ledger.reason_detail, ledger.created_at
+FROM inventory_ledger ledger JOIN items ON items.id = ledger.item_id
+WHERE ledger.reason = 'Mined'
+ORDER BY ledger.user_id, ledger.value_change DESC;
@@ -0,0 +1,7 @@
+-- Public profile settings.  Users without a row have the defaults.
+CREATE TABLE IF NOT EXISTS user_profiles (
+  user_id integer PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  hide_inventory boolean NOT NULL DEFAULT false,
+  -- Items or stacks shown on the profile, in order.  Ones that have since been spent are skipped.
+  showcase_item_uuids uuid[] NOT NULL DEFAULT '{}'
+);
@@ -0,0 +1,7 @@
+-- Achievements each user has unlocked.  `achievement_id` refers to `src/game/achievements.yml`.
+CREATE TABLE IF NOT EXISTS user_achievements (
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  achievement_id integer NOT NULL,
+  unlocked_at timestamp NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, achievement_id)
+);
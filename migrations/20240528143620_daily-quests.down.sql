@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_inventory_ledger_user_id_created_at;
+DROP TABLE IF EXISTS user_quest_completions;
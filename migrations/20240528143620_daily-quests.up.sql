@@ -0,0 +1,12 @@
+-- Daily quests each user has completed.  `quest_name` refers to `src/game/quests.yml`.
+CREATE TABLE IF NOT EXISTS user_quest_completions (
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- The UTC day the quest was offered on
+  day date NOT NULL,
+  quest_name text NOT NULL,
+  completed_at timestamp NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, day, quest_name)
+);
+
+-- Mining quest progress is counted from the ledger entries since the start of the day
+CREATE INDEX IF NOT EXISTS idx_inventory_ledger_user_id_created_at ON inventory_ledger (user_id, created_at);
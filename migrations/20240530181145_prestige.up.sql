@@ -0,0 +1,2 @@
+-- Number of times the user has prestiged, which permanently boosts their mining
+ALTER TABLE users ADD COLUMN IF NOT EXISTS prestige_level integer NOT NULL DEFAULT 0;
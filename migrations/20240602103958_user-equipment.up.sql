@@ -0,0 +1,12 @@
+-- Items each user has equipped.  Equipped items stay in the inventory and are unequipped when
+-- they're removed from it.
+CREATE TABLE IF NOT EXISTS user_equipment (
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- Name of an `EquipmentSlot`
+  slot text NOT NULL,
+  item_uuid uuid NOT NULL REFERENCES inventory(id) ON DELETE CASCADE,
+  equipped_at timestamp NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, slot)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_equipment_item_uuid ON user_equipment (item_uuid);
@@ -1,70 +1,657 @@
+// Credential checks return `Status` so it can be passed straight back to gRPC callers
+#![allow(clippy::result_large_err)]
+
+use std::{
+  net::IpAddr,
+  time::{Duration, Instant},
+};
+
+use argon2::Argon2;
 use base64::Engine;
+use dashmap::DashMap;
+use foundations::BootstrapResult;
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
 use scrypt::{
   password_hash::{
     rand_core::{OsRng, RngCore},
-    PasswordHash, PasswordHasher, PasswordVerifier, Salt, SaltString,
+    PasswordHash, PasswordHasher, Salt, SaltString,
   },
   Scrypt,
 };
-use tonic::Status;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tonic::{Request, Status};
+
+use crate::{
+  conf::{AuthSettings, PasswordHashAlgorithm, PasswordHashSettings, Settings},
+  db::{get_hashed_password, insert_api_key, update_hashed_password},
+  protos::{ApiKeyInfo, ApiKeyScope},
+};
+
+/// Prefix for API keys, used to tell them apart from session tokens.
+pub const API_KEY_PREFIX: &str = "mik_";
+/// Prefix for generated guest usernames.  Can't be used by regular accounts.
+const GUEST_USERNAME_PREFIX: &str = "guest-";
+
+static AUTH_SETTINGS: OnceCell<AuthSettings> = OnceCell::new();
+static PASSWORD_HASHER: OnceCell<PasswordHasherConfig> = OnceCell::new();
+static PASSWORD_HASH_SEMAPHORE: OnceCell<Semaphore> = OnceCell::new();
+
+lazy_static! {
+  static ref LOGIN_THROTTLES: DashMap<LoginThrottleKey, LoginThrottleState> = DashMap::new();
+}
+
+pub fn init_auth(settings: &Settings) -> BootstrapResult<()> {
+  AUTH_SETTINGS
+    .set(settings.auth.clone())
+    .map_err(|_| anyhow::anyhow!("Auth settings already initialized"))?;
+  PASSWORD_HASHER
+    .set(PasswordHasherConfig::new(&settings.auth.password_hash)?)
+    .map_err(|_| anyhow::anyhow!("Password hasher already initialized"))?;
+  PASSWORD_HASH_SEMAPHORE
+    .set(Semaphore::new(settings.auth.max_concurrent_password_hashes))
+    .map_err(|_| anyhow::anyhow!("Password hash semaphore already initialized"))?;
+
+  // Periodically drop throttle state for usernames and IPs that haven't failed a login or created a
+  // guest account in a while so the map doesn't grow without bound.
+  tokio::task::spawn(async move {
+    loop {
+      tokio::time::sleep(Duration::from_secs(60)).await;
+
+      let now = Instant::now();
+      LOGIN_THROTTLES.retain(|_, state| !state.is_stale(now, auth_settings()));
+    }
+  });
+
+  Ok(())
+}
+
+fn auth_settings() -> &'static AuthSettings {
+  AUTH_SETTINGS.get().expect("Auth settings not initialized")
+}
+
+/// Checks that a username and password are acceptable for a new account, returning an
+/// `invalid_argument` status describing the problem if not.
+pub fn validate_new_credentials(username: &str, password: &str) -> Result<(), Status> {
+  let settings = auth_settings();
+  validate_username(username, settings)?;
+  validate_password(password, settings)
+}
+
+fn validate_username(username: &str, settings: &AuthSettings) -> Result<(), Status> {
+  let is_allowed_char =
+    |c: char| c.is_ascii_alphanumeric() || settings.username_extra_chars.contains(c);
+
+  let len = username.chars().count();
+  if len < settings.min_username_length || len > settings.max_username_length {
+    return Err(Status::invalid_argument(format!(
+      "Username must be between {} and {} characters long",
+      settings.min_username_length, settings.max_username_length
+    )));
+  }
+  if !username.chars().all(is_allowed_char) {
+    return Err(Status::invalid_argument(format!(
+      "Username may only contain ASCII letters, numbers, and any of `{}`",
+      settings.username_extra_chars
+    )));
+  }
+  if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+    return Err(Status::invalid_argument(
+      "Username must start with a letter or number",
+    ));
+  }
+  if username
+    .to_ascii_lowercase()
+    .starts_with(GUEST_USERNAME_PREFIX)
+  {
+    return Err(Status::invalid_argument(format!(
+      "Username can't start with `{GUEST_USERNAME_PREFIX}`"
+    )));
+  }
+
+  // Strip separators so that names like "ad_min" can't be used to get around the reserved list
+  let normalized: String = username
+    .chars()
+    .filter(char::is_ascii_alphanumeric)
+    .map(|c| c.to_ascii_lowercase())
+    .collect();
+  if settings
+    .reserved_usernames
+    .iter()
+    .any(|reserved| reserved.eq_ignore_ascii_case(&normalized))
+  {
+    return Err(Status::invalid_argument("Username is reserved"));
+  }
+
+  Ok(())
+}
+
+fn validate_password(password: &str, settings: &AuthSettings) -> Result<(), Status> {
+  if password.chars().count() < settings.min_password_length {
+    return Err(Status::invalid_argument(format!(
+      "Password must be at least {} characters long",
+      settings.min_password_length
+    )));
+  }
+  if password.len() > settings.max_password_length {
+    return Err(Status::invalid_argument(format!(
+      "Password must be at most {} bytes long",
+      settings.max_password_length
+    )));
+  }
+
+  Ok(())
+}
 
-use crate::db::get_hashed_password;
+/// Runs a password hashing operation on the blocking thread pool.  The number of these that can run
+/// at once is capped so that a flood of login attempts can't starve the rest of the server.
+async fn run_password_hash<T: Send + 'static>(
+  f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, Status> {
+  let _permit = PASSWORD_HASH_SEMAPHORE
+    .get()
+    .expect("Password hash semaphore not initialized")
+    .acquire()
+    .await
+    .map_err(|_| Status::internal("Internal error"))?;
 
-pub fn hash_password(password: &str) -> Result<String, scrypt::password_hash::Error> {
+  tokio::task::spawn_blocking(f).await.map_err(|err| {
+    error!("Password hashing task failed: {err}");
+    Status::internal("Internal error")
+  })
+}
+
+/// Validated form of `PasswordHashSettings` used to hash new passwords and to check whether stored
+/// hashes are up to date.
+enum PasswordHasherConfig {
+  Scrypt(scrypt::Params),
+  Argon2id(argon2::Params),
+}
+
+impl PasswordHasherConfig {
+  fn new(settings: &PasswordHashSettings) -> anyhow::Result<Self> {
+    Ok(match settings.algorithm {
+      PasswordHashAlgorithm::Scrypt => Self::Scrypt(
+        scrypt::Params::new(
+          settings.scrypt_log_n,
+          settings.scrypt_r,
+          settings.scrypt_p,
+          scrypt::Params::RECOMMENDED_LEN,
+        )
+        .map_err(|err| anyhow::anyhow!("Invalid scrypt params: {err}"))?,
+      ),
+      PasswordHashAlgorithm::Argon2id => Self::Argon2id(
+        argon2::Params::new(
+          settings.argon2_m_cost,
+          settings.argon2_t_cost,
+          settings.argon2_p_cost,
+          None,
+        )
+        .map_err(|err| anyhow::anyhow!("Invalid argon2 params: {err}"))?,
+      ),
+    })
+  }
+
+  /// Returns true if `hash` was produced by a different algorithm or with different parameters than
+  /// this config would use.
+  fn is_outdated(&self, hash: &PasswordHash) -> bool {
+    match self {
+      Self::Scrypt(params) =>
+        hash.algorithm != scrypt::ALG_ID
+          || scrypt::Params::try_from(hash).map_or(true, |hash_params| {
+            hash_params.log_n() != params.log_n()
+              || hash_params.r() != params.r()
+              || hash_params.p() != params.p()
+          }),
+      Self::Argon2id(params) =>
+        hash.algorithm != argon2::ARGON2ID_IDENT
+          || hash.version != Some(argon2::Version::V0x13.into())
+          || argon2::Params::try_from(hash).map_or(true, |hash_params| {
+            hash_params.m_cost() != params.m_cost()
+              || hash_params.t_cost() != params.t_cost()
+              || hash_params.p_cost() != params.p_cost()
+          }),
+    }
+  }
+}
+
+fn password_hasher() -> &'static PasswordHasherConfig {
+  PASSWORD_HASHER
+    .get()
+    .expect("Password hasher not initialized")
+}
+
+fn compute_password_hash(
+  password: &str,
+  config: &PasswordHasherConfig,
+) -> Result<String, scrypt::password_hash::Error> {
   let salt = SaltString::generate(&mut OsRng);
-  let params = scrypt::Params::new(15, 2, 2, scrypt::Params::RECOMMENDED_LEN).unwrap();
-  let hash = Scrypt
-    .hash_password_customized(
+  let hash = match config {
+    PasswordHasherConfig::Scrypt(params) => Scrypt.hash_password_customized(
       password.as_bytes(),
       None,
       None,
-      params,
+      *params,
       Salt::try_from(salt.as_ref())?,
-    )?
-    .to_string();
-  Ok(hash)
+    )?,
+    PasswordHasherConfig::Argon2id(params) => Argon2::new(
+      argon2::Algorithm::Argon2id,
+      argon2::Version::V0x13,
+      params.clone(),
+    )
+    .hash_password(password.as_bytes(), &salt)?,
+  };
+  Ok(hash.to_string())
+}
+
+pub async fn hash_password(password: &str) -> Result<String, Status> {
+  let password = password.to_owned();
+  run_password_hash(move || compute_password_hash(&password, password_hasher()))
+    .await?
+    .map_err(|err| {
+      error!("Error hashing password: {err}");
+      Status::internal("Internal error")
+    })
 }
 
+/// Verifies a password against a stored hash made with any supported algorithm.  If it matches but
+/// the hash is outdated, also returns a new hash of the password made with the current config.
 fn verify_password_with_hash(
   password: &str,
   hash: &str,
-) -> Result<(), scrypt::password_hash::Error> {
-  let hash = PasswordHash::new(&hash)?;
-  Scrypt.verify_password(password.as_bytes(), &hash)
+  config: &PasswordHasherConfig,
+) -> Result<Option<String>, scrypt::password_hash::Error> {
+  let hash = PasswordHash::new(hash)?;
+  hash.verify_password(&[&Scrypt, &Argon2::default()], password)?;
+
+  if config.is_outdated(&hash) {
+    compute_password_hash(password, config).map(Some)
+  } else {
+    Ok(None)
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum LoginThrottleKey {
+  Username(String),
+  Ip(IpAddr),
+  /// Guest account creations, which are counted like failed logins
+  GuestIp(IpAddr),
+}
+
+struct LoginThrottleState {
+  window_start: Instant,
+  /// Failed attempts since `window_start`
+  failures: u32,
+  /// Number of times this key has been locked out.  Each lockout is twice as long as the last.
+  lockout_count: u32,
+  locked_until: Option<Instant>,
+}
+
+impl LoginThrottleState {
+  fn new(now: Instant) -> Self {
+    Self {
+      window_start: now,
+      failures: 0,
+      lockout_count: 0,
+      locked_until: None,
+    }
+  }
+
+  fn remaining_lockout(&self, now: Instant) -> Option<Duration> {
+    self
+      .locked_until
+      .and_then(|locked_until| locked_until.checked_duration_since(now))
+      .filter(|remaining| !remaining.is_zero())
+  }
+
+  fn record_failure(&mut self, now: Instant, max_failures: u32, settings: &AuthSettings) {
+    if now.duration_since(self.window_start).as_secs() >= settings.login_attempt_window_seconds {
+      self.window_start = now;
+      self.failures = 0;
+    }
+
+    self.failures += 1;
+    if self.failures < max_failures {
+      return;
+    }
+
+    let lockout_seconds = settings
+      .login_lockout_base_seconds
+      .saturating_mul(1 << self.lockout_count.min(32))
+      .min(settings.login_lockout_max_seconds);
+    self.locked_until = Some(now + Duration::from_secs(lockout_seconds));
+    self.lockout_count += 1;
+    self.window_start = now;
+    self.failures = 0;
+  }
+
+  /// Returns true if nothing has happened for long enough that this state can be forgotten,
+  /// resetting the lockout backoff.
+  fn is_stale(&self, now: Instant, settings: &AuthSettings) -> bool {
+    let idle_secs = settings
+      .login_attempt_window_seconds
+      .max(settings.login_lockout_max_seconds);
+    self.remaining_lockout(now).is_none()
+      && now.duration_since(self.window_start).as_secs() >= idle_secs
+  }
+}
+
+fn login_throttle_keys(
+  username: &str,
+  client_ip: Option<IpAddr>,
+  settings: &AuthSettings,
+) -> Vec<(LoginThrottleKey, u32)> {
+  let mut keys = vec![(
+    LoginThrottleKey::Username(username.to_lowercase()),
+    settings.max_failed_logins_per_username,
+  )];
+  if let Some(ip) = client_ip {
+    keys.push((LoginThrottleKey::Ip(ip), settings.max_failed_logins_per_ip));
+  }
+  keys
+}
+
+fn check_login_throttle(username: &str, client_ip: Option<IpAddr>) -> Result<(), Status> {
+  let now = Instant::now();
+  for (key, _) in login_throttle_keys(username, client_ip, auth_settings()) {
+    let remaining = LOGIN_THROTTLES
+      .get(&key)
+      .and_then(|state| state.remaining_lockout(now));
+    if let Some(remaining) = remaining {
+      return Err(Status::resource_exhausted(format!(
+        "Too many failed login attempts; try again in {} seconds",
+        remaining.as_secs().max(1)
+      )));
+    }
+  }
+
+  Ok(())
+}
+
+/// Counts a guest account creation against the client's IP, failing if it has created too many
+/// recently.  Uses the same windows and lockouts as failed logins.
+pub fn check_guest_creation_throttle(client_ip: Option<IpAddr>) -> Result<(), Status> {
+  let Some(ip) = client_ip else {
+    return Ok(());
+  };
+  let settings = auth_settings();
+  let now = Instant::now();
+
+  let mut state = LOGIN_THROTTLES
+    .entry(LoginThrottleKey::GuestIp(ip))
+    .or_insert_with(|| LoginThrottleState::new(now));
+  if let Some(remaining) = state.remaining_lockout(now) {
+    return Err(Status::resource_exhausted(format!(
+      "Too many guest accounts created; try again in {} seconds",
+      remaining.as_secs().max(1)
+    )));
+  }
+  state.record_failure(now, settings.max_guest_accounts_per_ip, settings);
+
+  Ok(())
+}
+
+fn record_login_failure(username: &str, client_ip: Option<IpAddr>) {
+  let settings = auth_settings();
+  let now = Instant::now();
+  for (key, max_failures) in login_throttle_keys(username, client_ip, settings) {
+    LOGIN_THROTTLES
+      .entry(key)
+      .or_insert_with(|| LoginThrottleState::new(now))
+      .record_failure(now, max_failures, settings);
+  }
+}
+
+fn record_login_success(username: &str) {
+  LOGIN_THROTTLES.remove(&LoginThrottleKey::Username(username.to_lowercase()));
+}
+
+/// Returns the IP address of the client that sent the request, if known.
+/// Returns the last address in an `X-Forwarded-For` header, which is the one appended by the
+/// proxy directly in front of us.  Earlier entries are supplied by the client and can be spoofed.
+fn parse_forwarded_for(header: &str) -> Option<IpAddr> {
+  header.rsplit(',').next()?.trim().parse().ok()
+}
+
+pub fn client_ip<T>(req: &Request<T>) -> Option<IpAddr> {
+  if auth_settings().trust_x_forwarded_for {
+    let forwarded_ip = req
+      .metadata()
+      .get("x-forwarded-for")
+      .and_then(|val| val.to_str().ok())
+      .and_then(parse_forwarded_for);
+    if forwarded_ip.is_some() {
+      return forwarded_ip;
+    }
+  }
+
+  req.remote_addr().map(|addr| addr.ip())
 }
 
 /// Returns the user id if the username and password are correct.
-pub async fn verify_password(username: &str, password: &str) -> Result<i32, Status> {
+///
+/// Failed attempts are throttled per username and per client IP, with exponentially increasing
+/// lockouts.
+pub async fn verify_password(
+  username: &str,
+  password: &str,
+  client_ip: Option<IpAddr>,
+) -> Result<i32, Status> {
+  check_login_throttle(username, client_ip)?;
+
+  // No valid password is this long, so don't bother spending time hashing it
+  if password.len() > auth_settings().max_password_length {
+    record_login_failure(username, client_ip);
+    return Err(Status::unauthenticated("Invalid username or password"));
+  }
+
   let (user_id, hash) = match get_hashed_password(username).await? {
     Some((user_id, hash)) => (user_id, hash),
     None => {
       // Perform a dummy hash to make the function take a consistent amount of time to prevent user
       // enumeration attacks.
-      let _ = hash_password(password);
+      let _ = hash_password(password).await;
 
+      record_login_failure(username, client_ip);
       return Err(Status::unauthenticated("Invalid username or password"));
     },
   };
 
-  match verify_password_with_hash(password, &hash) {
-    Ok(_) => Ok(user_id),
+  let owned_password = password.to_owned();
+  let verify_res =
+    run_password_hash(move || verify_password_with_hash(&owned_password, &hash, password_hasher()))
+      .await?;
+  match verify_res {
+    Ok(new_hash) => {
+      record_login_success(username);
+
+      if let Some(new_hash) = new_hash {
+        // Failing to store the upgraded hash isn't fatal; the old one still works and we'll try
+        // again on the next login.
+        match update_hashed_password(user_id, &new_hash).await {
+          Ok(()) => info!("Upgraded password hash for user {username}"),
+          Err(err) => error!("Failed to store upgraded password hash for user {username}: {err}"),
+        }
+      }
+
+      Ok(user_id)
+    },
     Err(err) => {
       error!("Error verifying password for user {username}: {err}");
+      record_login_failure(username, client_ip);
       Err(Status::unauthenticated("Invalid username or password"))
     },
   }
 }
 
+pub fn generate_guest_username() -> String {
+  const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+  let mut rng = OsRng;
+  let suffix: String = (0..10)
+    .map(|_| CHARSET[(rng.next_u32() as usize) % CHARSET.len()] as char)
+    .collect();
+  format!("{GUEST_USERNAME_PREFIX}{suffix}")
+}
+
 pub fn generate_session_token() -> String {
   let mut rng = OsRng;
   let mut bytes = [0u8; 64];
   rng.fill_bytes(&mut bytes);
-  base64::engine::general_purpose::STANDARD.encode(&bytes)
+  base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+/// Hashes an API key for storage and lookup.  API keys are high-entropy, so a fast hash is enough.
+pub fn hash_api_key(api_key: &str) -> String {
+  base64::engine::general_purpose::STANDARD.encode(Sha256::digest(api_key.as_bytes()))
+}
+
+/// Generates a new API key for the user and stores its hash, returning the full key along with its
+/// info.  The full key can't be recovered after this.
+pub async fn create_api_key(
+  user_id: i32,
+  name: &str,
+  scopes: &[ApiKeyScope],
+) -> Result<(String, ApiKeyInfo), Status> {
+  let name = name.trim();
+  if name.is_empty() || name.chars().count() > 64 {
+    return Err(Status::invalid_argument(
+      "API key name must be between 1 and 64 characters long",
+    ));
+  }
+  if scopes.is_empty() {
+    return Err(Status::invalid_argument(
+      "API key must have at least one scope",
+    ));
+  }
+
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  let api_key = format!(
+    "{API_KEY_PREFIX}{}",
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+  );
+  let key_prefix = &api_key[..API_KEY_PREFIX.len() + 6];
+
+  let info = insert_api_key(
+    user_id,
+    name,
+    &hash_api_key(&api_key),
+    key_prefix,
+    scopes,
+    auth_settings().max_api_keys_per_user,
+  )
+  .await?;
+  Ok((api_key, info))
 }
 
 #[test]
 fn test_hash_password() {
   let password = "password";
-  let hash = hash_password(password).unwrap();
-  assert!(verify_password_with_hash(password, &hash).is_ok());
+  let config = PasswordHasherConfig::new(&PasswordHashSettings::default()).unwrap();
+  let hash = compute_password_hash(password, &config).unwrap();
+  assert_eq!(
+    verify_password_with_hash(password, &hash, &config),
+    Ok(None)
+  );
+  assert!(verify_password_with_hash("wrong", &hash, &config).is_err());
+}
+
+#[test]
+fn test_outdated_password_hash_is_upgraded() {
+  let password = "password";
+  let old_config = PasswordHasherConfig::new(&PasswordHashSettings {
+    scrypt_log_n: 10,
+    ..Default::default()
+  })
+  .unwrap();
+  let old_hash = compute_password_hash(password, &old_config).unwrap();
+
+  let new_config = PasswordHasherConfig::new(&PasswordHashSettings {
+    algorithm: PasswordHashAlgorithm::Argon2id,
+    argon2_m_cost: 1024,
+    ..Default::default()
+  })
+  .unwrap();
+  let new_hash = verify_password_with_hash(password, &old_hash, &new_config)
+    .unwrap()
+    .expect("Outdated hash should have been upgraded");
+  assert!(new_hash.starts_with("$argon2id$"));
+
+  // The upgraded hash verifies and is considered current
+  assert_eq!(
+    verify_password_with_hash(password, &new_hash, &new_config),
+    Ok(None)
+  );
+}
+
+#[test]
+fn test_validate_new_credentials() {
+  let settings = AuthSettings::default();
+
+  assert!(validate_username("ameo", &settings).is_ok());
+  assert!(validate_username("rock_collector-99", &settings).is_ok());
+  assert!(validate_username("ab", &settings).is_err());
+  assert!(validate_username(&"a".repeat(25), &settings).is_err());
+  assert!(validate_username("аmeo", &settings).is_err());
+  assert!(validate_username("has space", &settings).is_err());
+  assert!(validate_username("_ameo", &settings).is_err());
+  assert!(validate_username("Ad_Min", &settings).is_err());
+  assert!(validate_username("Guest-abc", &settings).is_err());
+  assert!(validate_username(&generate_guest_username(), &settings).is_err());
+
+  assert!(validate_password("hunter22", &settings).is_ok());
+  assert!(validate_password("hunter2", &settings).is_err());
+  assert!(validate_password(&"a".repeat(257), &settings).is_err());
+}
+
+#[test]
+fn test_login_throttle_backoff() {
+  let settings = AuthSettings::default();
+  let start = Instant::now();
+  let mut state = LoginThrottleState::new(start);
+
+  for _ in 0..4 {
+    state.record_failure(start, 5, &settings);
+  }
+  assert!(state.remaining_lockout(start).is_none());
+
+  state.record_failure(start, 5, &settings);
+  assert_eq!(
+    state.remaining_lockout(start),
+    Some(Duration::from_secs(30))
+  );
+
+  let after_first_lockout = start + Duration::from_secs(30);
+  assert!(state.remaining_lockout(after_first_lockout).is_none());
+  for _ in 0..5 {
+    state.record_failure(after_first_lockout, 5, &settings);
+  }
+  assert_eq!(
+    state.remaining_lockout(after_first_lockout),
+    Some(Duration::from_secs(60))
+  );
+  assert!(!state.is_stale(after_first_lockout, &settings));
+  assert!(state.is_stale(
+    after_first_lockout + Duration::from_secs(60 * 60),
+    &settings
+  ));
+}
+
+#[test]
+fn test_parse_forwarded_for() {
+  let proxy_seen: IpAddr = "203.0.113.7".parse().unwrap();
+  assert_eq!(parse_forwarded_for("203.0.113.7"), Some(proxy_seen));
+  assert_eq!(
+    parse_forwarded_for("198.51.100.1, 10.0.0.1 , 203.0.113.7"),
+    Some(proxy_seen)
+  );
+  // A spoofed leading entry doesn't change the address the proxy appended
+  assert_eq!(
+    parse_forwarded_for("1.2.3.4, 203.0.113.7"),
+    Some(proxy_seen)
+  );
+  assert_eq!(parse_forwarded_for("1.2.3.4, garbage"), None);
 }
@@ -8,9 +8,39 @@ pub struct ServerSettings {
   pub port: u16,
 }
 
+#[settings]
+pub enum DatabaseSslMode {
+  Disable,
+  Allow,
+  #[default]
+  Prefer,
+  Require,
+  VerifyCa,
+  VerifyFull,
+}
+
+#[serde_inline_default]
+#[settings]
+pub struct ReplicaSettings {
+  /// Postgres connection URL for a read replica.  If set, heavy read-only queries are sent to it
+  /// while it's healthy.  All other connection options are shared with the primary.
+  pub url: Option<String>,
+  #[serde_inline_default(20)]
+  pub max_connections: u32,
+  /// If the replica falls further behind the primary than this, reads go to the primary until it
+  /// catches up.
+  #[serde_inline_default(10)]
+  pub max_lag_seconds: u64,
+  #[serde_inline_default(5)]
+  pub health_check_interval_seconds: u64,
+}
+
 #[serde_inline_default]
 #[settings]
 pub struct DatabaseSettings {
+  /// Postgres connection URL.  If set, it's used in place of `host`, `port`, `username`,
+  /// `password`, and `database`.  The other options below are applied on top of it.
+  pub url: Option<String>,
   #[serde_inline_default("localhost".to_owned())]
   pub host: String,
   #[serde_inline_default(5432)]
@@ -20,6 +50,60 @@ pub struct DatabaseSettings {
   pub password: String,
   #[serde_inline_default("mine_idler".to_owned())]
   pub database: String,
+  /// Apply any pending migrations from `migrations/` (embedded in the binary) on startup.
+  #[serde_inline_default(false)]
+  pub run_migrations: bool,
+  /// One of `disable`, `allow`, `prefer`, `require`, `verify_ca`, or `verify_full`.  If unset,
+  /// uses the mode from `url` or else `prefer`.
+  pub ssl_mode: Option<DatabaseSslMode>,
+  /// Path to a PEM file containing the CA certificate(s) used to verify the server for
+  /// `verify_ca` and `verify_full`.
+  pub ssl_root_cert: Option<String>,
+  #[serde_inline_default("mine-idler".to_owned())]
+  pub application_name: String,
+  /// Postgres `statement_timeout` for all connections in the pool.  0 disables the timeout.
+  #[serde_inline_default(0)]
+  pub statement_timeout_ms: u64,
+  #[serde_inline_default(20)]
+  pub max_connections: u32,
+  #[serde_inline_default(1)]
+  pub min_connections: u32,
+  #[serde_inline_default(600)]
+  pub max_lifetime_seconds: u64,
+  #[serde_inline_default(600)]
+  pub idle_timeout_seconds: u64,
+  /// Max time to wait for a connection from the pool before failing the query.
+  #[serde_inline_default(30)]
+  pub acquire_timeout_seconds: u64,
+  pub replica: ReplicaSettings,
+}
+
+#[settings]
+pub enum PasswordHashAlgorithm {
+  #[default]
+  Scrypt,
+  Argon2id,
+}
+
+#[serde_inline_default]
+#[settings]
+pub struct PasswordHashSettings {
+  /// Algorithm used to hash new passwords.  Either `scrypt` or `argon2id`.  Stored hashes that use
+  /// a different algorithm or different parameters are re-hashed when their user next logs in.
+  pub algorithm: PasswordHashAlgorithm,
+  #[serde_inline_default(15)]
+  pub scrypt_log_n: u8,
+  #[serde_inline_default(2)]
+  pub scrypt_r: u32,
+  #[serde_inline_default(2)]
+  pub scrypt_p: u32,
+  /// Argon2 memory cost in KiB
+  #[serde_inline_default(19 * 1024)]
+  pub argon2_m_cost: u32,
+  #[serde_inline_default(2)]
+  pub argon2_t_cost: u32,
+  #[serde_inline_default(1)]
+  pub argon2_p_cost: u32,
 }
 
 #[serde_inline_default]
@@ -28,6 +112,72 @@ pub struct AuthSettings {
   // 6 months
   #[serde_inline_default(60 * 60 * 24 * 30 * 6)]
   pub session_token_lifetime_seconds: u64,
+  // 1 week
+  #[serde_inline_default(60 * 60 * 24 * 7)]
+  pub guest_session_token_lifetime_seconds: u64,
+  #[serde_inline_default(3)]
+  pub min_username_length: usize,
+  #[serde_inline_default(24)]
+  pub max_username_length: usize,
+  /// Characters allowed in usernames in addition to ASCII letters and digits.
+  #[serde_inline_default("_-".to_owned())]
+  pub username_extra_chars: String,
+  /// Usernames that can't be registered.  Matched case-insensitively and ignoring any of
+  /// `username_extra_chars`.
+  #[serde_inline_default(vec![
+    "admin".to_owned(),
+    "administrator".to_owned(),
+    "moderator".to_owned(),
+    "mod".to_owned(),
+    "root".to_owned(),
+    "system".to_owned(),
+    "support".to_owned(),
+  ])]
+  pub reserved_usernames: Vec<String>,
+  #[serde_inline_default(8)]
+  pub min_password_length: usize,
+  /// Max password length in bytes.  Passwords are hashed with scrypt, so this bounds the work done
+  /// per registration/login attempt.
+  #[serde_inline_default(256)]
+  pub max_password_length: usize,
+  /// Failed login attempts are counted over windows of this length.
+  #[serde_inline_default(60 * 15)]
+  pub login_attempt_window_seconds: u64,
+  /// Failed logins for a single username within one window before it gets locked out.
+  #[serde_inline_default(5)]
+  pub max_failed_logins_per_username: u32,
+  /// Failed logins from a single IP within one window before it gets locked out.
+  #[serde_inline_default(20)]
+  pub max_failed_logins_per_ip: u32,
+  /// Guest accounts that can be created from a single IP within one window before it gets locked
+  /// out.
+  #[serde_inline_default(5)]
+  pub max_guest_accounts_per_ip: u32,
+  /// Length of the first lockout.  Doubles with each subsequent lockout of the same username or
+  /// IP.
+  #[serde_inline_default(30)]
+  pub login_lockout_base_seconds: u64,
+  #[serde_inline_default(60 * 60)]
+  pub login_lockout_max_seconds: u64,
+  /// Use the last address in `X-Forwarded-For` as the client IP.  Only enable this when running
+  /// behind a reverse proxy that appends to it.
+  #[serde_inline_default(false)]
+  pub trust_x_forwarded_for: bool,
+  /// Max number of password hashes/verifications that can run at once on the blocking thread pool.
+  #[serde_inline_default(4)]
+  pub max_concurrent_password_hashes: usize,
+  pub password_hash: PasswordHashSettings,
+  #[serde_inline_default(10)]
+  pub max_api_keys_per_user: i64,
+}
+
+#[serde_inline_default]
+#[settings]
+pub struct InventorySettings {
+  /// Number of equal-width quality buckets in aggregated inventory histograms and item stacks.
+  /// Stored histograms are rebuilt on startup when this changes.
+  #[serde_inline_default(32)]
+  pub quality_histogram_buckets: usize,
 }
 
 #[settings]
@@ -38,4 +188,5 @@ pub struct Settings {
   pub server: ServerSettings,
   pub database: DatabaseSettings,
   pub auth: AuthSettings,
+  pub inventory: InventorySettings,
 }
@@ -1,66 +1,323 @@
-use std::{cmp::Reverse, time::Duration};
+use std::{
+  future::Future,
+  str::FromStr,
+  sync::atomic::{AtomicBool, Ordering},
+  time::Duration,
+};
 
+use chrono::{NaiveDate, NaiveDateTime};
 use foundations::BootstrapResult;
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 use once_cell::sync::OnceCell;
 use sqlx::{
+  migrate::Migrator,
   pool::PoolOptions,
-  postgres::{PgConnectOptions, PgQueryResult},
+  postgres::{PgConnectOptions, PgSslMode},
   FromRow, Pool, Postgres,
 };
 use tonic::Status;
 use uuid::Uuid;
 
 use crate::{
-  auth::hash_password,
-  conf::Settings,
+  auth::{hash_api_key, hash_password, validate_new_credentials},
+  conf::{DatabaseSettings, DatabaseSslMode, ReplicaSettings, Settings},
   game::{
-    items::{get_item_display_name_by_id, populate_items_table},
-    upgrades::{get_inventory_upgrade_cost, BASE_INVENTORY_SIZE, INVENTORY_CAPACITY_PER_UPGRADE},
+    debit::{select_lowest_quality_first, select_minimal_overshoot, DebitStrategy},
+    items::{
+      compute_item_value, get_item_display_name_by_id, get_item_rarity_tier, get_item_stack_size,
+      item_descriptors, populate_items_table,
+    },
+    stacks::{
+      histogram_min_quality, quality_histogram_buckets, rebucket_histogram, stack_slots,
+      HistogramDelta, ItemStack,
+    },
+    upgrades::{get_inventory_capacity, get_inventory_upgrade_cost},
   },
   protos::{
-    AggregatedInventory, AggregatedItemCount, HiscoreEntry, Item, ItemCost, ItemDescriptor,
-    ItemQualityHistogram, SortBy, SortDirection, StorageUpgrades, Upgrades, UserAccountInfo,
+    AggregatedInventory, AggregatedItemCount, ApiKeyInfo, ApiKeyScope, BestFind, BestFindKind,
+    EquipmentSlot, EquippedItem, HiscoreEntry, Item, ItemCost, ItemCostDebit, ItemDescriptor,
+    ItemQualityHistogram, LedgerEntry, LedgerReason, LocationStats, PlayerProfile, ProfileSettings,
+    SortBy, SortDirection, StorageUpgrades, Upgrades, UserAccountInfo, UserStats,
   },
 };
 
 static DB_POOL: OnceCell<Pool<Postgres>> = OnceCell::new();
+static REPLICA_POOL: OnceCell<Pool<Postgres>> = OnceCell::new();
+static REPLICA_HEALTHY: AtomicBool = AtomicBool::new(false);
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
 
 pub async fn init_db(settings: &Settings) -> BootstrapResult<()> {
-  let pool_options = PoolOptions::new()
-    .max_connections(20)
-    .min_connections(1)
-    .max_lifetime(Duration::from_secs(600));
-  let pool = pool_options
-    .connect_with(
-      PgConnectOptions::new()
-        .host(&settings.database.host)
-        .port(settings.database.port)
-        .username(&settings.database.username)
-        .password(&settings.database.password)
-        .database(&settings.database.database),
-    )
+  init_db_pool(settings).await?;
+
+  if settings.database.run_migrations {
+    run_migrations().await?;
+  }
+
+  populate_items_table().await?;
+  if quality_histograms_outdated().await? {
+    rebuild_quality_histograms().await?;
+  }
+  stack_inventory_items().await?;
+  start_guest_user_cleanup(Duration::from_secs(
+    settings.auth.guest_session_token_lifetime_seconds,
+  ));
+
+  Ok(())
+}
+
+fn build_connect_options(settings: &DatabaseSettings) -> anyhow::Result<PgConnectOptions> {
+  let mut options = match &settings.url {
+    Some(url) => PgConnectOptions::from_str(url)?,
+    None => PgConnectOptions::new()
+      .host(&settings.host)
+      .port(settings.port)
+      .username(&settings.username)
+      .password(&settings.password)
+      .database(&settings.database),
+  };
+
+  if let Some(ssl_mode) = &settings.ssl_mode {
+    options = options.ssl_mode(match ssl_mode {
+      DatabaseSslMode::Disable => PgSslMode::Disable,
+      DatabaseSslMode::Allow => PgSslMode::Allow,
+      DatabaseSslMode::Prefer => PgSslMode::Prefer,
+      DatabaseSslMode::Require => PgSslMode::Require,
+      DatabaseSslMode::VerifyCa => PgSslMode::VerifyCa,
+      DatabaseSslMode::VerifyFull => PgSslMode::VerifyFull,
+    });
+  }
+  if let Some(ssl_root_cert) = &settings.ssl_root_cert {
+    options = options.ssl_root_cert(ssl_root_cert);
+  }
+  options = options.application_name(&settings.application_name);
+  if settings.statement_timeout_ms > 0 {
+    options = options.options([(
+      "statement_timeout",
+      settings.statement_timeout_ms.to_string(),
+    )]);
+  }
+
+  Ok(options)
+}
+
+fn build_pool_options(settings: &DatabaseSettings) -> PoolOptions<Postgres> {
+  PoolOptions::new()
+    .max_connections(settings.max_connections)
+    .min_connections(settings.min_connections)
+    .max_lifetime(Duration::from_secs(settings.max_lifetime_seconds))
+    .idle_timeout(Duration::from_secs(settings.idle_timeout_seconds))
+    .acquire_timeout(Duration::from_secs(settings.acquire_timeout_seconds))
+}
+
+async fn connect_pool(settings: &DatabaseSettings) -> anyhow::Result<Pool<Postgres>> {
+  let pool = build_pool_options(settings)
+    .connect_with(build_connect_options(settings)?)
     .await?;
+  Ok(pool)
+}
+
+/// Creates the replica pool without connecting so that an unreachable replica doesn't prevent
+/// startup; the health checker takes care of routing reads to the primary until it's available.
+fn connect_replica_pool(settings: &DatabaseSettings, url: &str) -> anyhow::Result<Pool<Postgres>> {
+  let replica_settings = DatabaseSettings {
+    url: Some(url.to_owned()),
+    max_connections: settings.replica.max_connections,
+    min_connections: settings
+      .min_connections
+      .min(settings.replica.max_connections),
+    ..settings.clone()
+  };
+  Ok(
+    build_pool_options(&replica_settings)
+      .connect_lazy_with(build_connect_options(&replica_settings)?),
+  )
+}
+
+/// Returns how far the replica is behind the primary.  Returns zero if it has replayed all the WAL
+/// it has received, since `pg_last_xact_replay_timestamp` keeps growing while the primary is idle.
+async fn get_replica_lag(replica: &Pool<Postgres>) -> sqlx::Result<Duration> {
+  let lag_seconds = sqlx::query_scalar!(
+    "SELECT CASE WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 ELSE \
+     COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0) END::float8 AS \
+     \"lag_seconds!\""
+  )
+  .fetch_one(replica)
+  .await?;
+  Ok(Duration::from_secs_f64(lag_seconds.max(0.)))
+}
+
+fn start_replica_health_checker(replica: Pool<Postgres>, settings: &ReplicaSettings) {
+  let max_lag = Duration::from_secs(settings.max_lag_seconds);
+  let check_interval = Duration::from_secs(settings.health_check_interval_seconds);
+
+  tokio::task::spawn(async move {
+    loop {
+      let healthy = match tokio::time::timeout(check_interval, get_replica_lag(&replica)).await {
+        Ok(Ok(lag)) => {
+          crate::metrics::db::replica_lag_ms().set(lag.as_millis() as _);
+          if lag > max_lag {
+            warn!("Replica is lagging by {lag:?}");
+          }
+          lag <= max_lag
+        },
+        Ok(Err(err)) => {
+          warn!("Error checking replica lag: {err}");
+          false
+        },
+        Err(_) => {
+          warn!("Timed out checking replica lag");
+          false
+        },
+      };
+
+      set_replica_healthy(healthy);
+      tokio::time::sleep(check_interval).await;
+    }
+  });
+}
+
+fn set_replica_healthy(healthy: bool) {
+  let was_healthy = REPLICA_HEALTHY.swap(healthy, Ordering::Relaxed);
+  if healthy != was_healthy {
+    if healthy {
+      info!("Routing reads to replica");
+    } else {
+      warn!("Routing reads to primary until replica recovers");
+    }
+  }
+  crate::metrics::db::replica_healthy().set(healthy as _);
+}
+
+/// Periodically records connection counts for the pool so utilization can be tracked.
+fn start_pool_metrics_recorder(pool: Pool<Postgres>, pool_name: &'static str) {
+  crate::metrics::db::pool_max_connections(pool_name)
+    .set(pool.options().get_max_connections() as _);
+
+  tokio::task::spawn(async move {
+    loop {
+      crate::metrics::db::pool_connections(pool_name).set(pool.size() as _);
+      crate::metrics::db::pool_idle_connections(pool_name).set(pool.num_idle() as _);
+
+      tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+  });
+}
+
+/// Deletes guest users that have no sessions left that `validate_session_token` would accept, since
+/// nobody can log into them anymore.  Everything they own is removed by cascading deletes.
+pub async fn delete_expired_guest_users(
+  guest_session_token_lifetime: Duration,
+) -> sqlx::Result<u64> {
+  let cutoff = chrono::Utc::now().naive_utc()
+    - chrono::Duration::from_std(guest_session_token_lifetime).unwrap();
+  // `last_login` is set when the user is created, which keeps guests whose first session hasn't
+  // been inserted yet from being deleted
+  let res = sqlx::query!(
+    "DELETE FROM users u WHERE u.is_guest AND u.last_login < $1 AND NOT EXISTS (SELECT 1 FROM \
+     sessions s WHERE s.user_id = u.id AND s.created_at >= $1)",
+    cutoff,
+  )
+  .execute(pool())
+  .await?;
+  Ok(res.rows_affected())
+}
+
+fn start_guest_user_cleanup(guest_session_token_lifetime: Duration) {
+  tokio::task::spawn(async move {
+    loop {
+      match delete_expired_guest_users(guest_session_token_lifetime).await {
+        Ok(0) => (),
+        Ok(count) => info!("Deleted {count} expired guest users"),
+        Err(err) => error!("Error deleting expired guest users: {err}"),
+      }
+
+      tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+  });
+}
+
+pub async fn init_db_pool(settings: &Settings) -> BootstrapResult<()> {
+  let pool = connect_pool(&settings.database).await?;
   info!("Database pool initialized");
+  start_pool_metrics_recorder(pool.clone(), "primary");
 
   DB_POOL
     .set(pool)
     .map_err(|_| anyhow::anyhow!("Database pool already initialized"))?;
 
-  populate_items_table().await?;
+  if let Some(replica_url) = &settings.database.replica.url {
+    let replica = connect_replica_pool(&settings.database, replica_url)?;
+    start_pool_metrics_recorder(replica.clone(), "replica");
+    start_replica_health_checker(replica.clone(), &settings.database.replica);
+
+    REPLICA_POOL
+      .set(replica)
+      .map_err(|_| anyhow::anyhow!("Replica pool already initialized"))?;
+    info!("Replica pool initialized");
+  }
+
+  Ok(())
+}
+
+/// Applies any migrations that haven't yet been run against the database.
+pub async fn run_migrations() -> BootstrapResult<()> {
+  MIGRATOR.run(pool()).await?;
+  info!("Database migrations applied");
 
   Ok(())
 }
 
 pub fn pool() -> &'static Pool<Postgres> { DB_POOL.get().expect("Database pool not initialized") }
 
+/// Pool for read-only queries that can tolerate slightly stale data.  This is the replica if one is
+/// configured and it's reachable and caught up, otherwise the primary.
+fn read_pool() -> &'static Pool<Postgres> {
+  match REPLICA_POOL.get() {
+    Some(replica) if REPLICA_HEALTHY.load(Ordering::Relaxed) => replica,
+    _ => pool(),
+  }
+}
+
+/// Runs a read-only query against `read_pool`.  If that's the replica and it can't be reached, the
+/// query is retried on the primary, and reads go to the primary until the next successful health
+/// check.
+async fn with_read_pool<T, Fut>(query: impl Fn(&'static Pool<Postgres>) -> Fut) -> sqlx::Result<T>
+where
+  Fut: Future<Output = sqlx::Result<T>>,
+{
+  let read_pool = read_pool();
+  match query(read_pool).await {
+    Err(err) if is_connection_error(&err) && !std::ptr::eq(read_pool, pool()) => {
+      warn!("Error querying replica; retrying on primary: {err}");
+      set_replica_healthy(false);
+      query(pool()).await
+    },
+    res => res,
+  }
+}
+
+fn is_connection_error(err: &sqlx::Error) -> bool {
+  matches!(
+    err,
+    sqlx::Error::Io(_)
+      | sqlx::Error::Tls(_)
+      | sqlx::Error::PoolTimedOut
+      | sqlx::Error::PoolClosed
+      | sqlx::Error::WorkerCrashed
+  )
+}
+
 /// If the session token is valid, returns the ID of the logged-in user.
+///
+/// Sessions belonging to guest accounts expire after `guest_session_token_lifetime` instead.
 pub async fn validate_session_token(
   session_token: &str,
   session_token_lifetime: Duration,
+  guest_session_token_lifetime: Duration,
 ) -> Result<Option<i32>, Status> {
   let session = sqlx::query!(
-    "SELECT user_id, created_at FROM sessions WHERE token = $1",
+    "SELECT s.user_id, s.created_at, u.is_guest FROM sessions s INNER JOIN users u ON s.user_id = \
+     u.id WHERE s.token = $1",
     session_token,
   )
   .fetch_optional(pool())
@@ -74,9 +331,14 @@ pub async fn validate_session_token(
     return Ok(None);
   };
 
+  let lifetime = if session.is_guest {
+    guest_session_token_lifetime
+  } else {
+    session_token_lifetime
+  };
   let created_at = session.created_at;
   let now = chrono::Utc::now().naive_utc();
-  let expiry = created_at + chrono::Duration::from_std(session_token_lifetime).unwrap();
+  let expiry = created_at + chrono::Duration::from_std(lifetime).unwrap();
   if now > expiry {
     return Err(Status::unauthenticated("Session token expired"));
   }
@@ -84,9 +346,139 @@ pub async fn validate_session_token(
   Ok(Some(session.user_id))
 }
 
+pub struct ApiKeyCredentials {
+  pub user_id: i32,
+  pub scopes: Vec<ApiKeyScope>,
+}
+
+fn parse_api_key_scopes(scopes: &[String]) -> Vec<ApiKeyScope> {
+  scopes
+    .iter()
+    .filter_map(|scope| ApiKeyScope::from_str_name(scope))
+    .collect()
+}
+
+/// If the API key is valid and not revoked, returns the ID of the user it belongs to along with its
+/// scopes.  Also records that the key was used.
+pub async fn validate_api_key(api_key: &str) -> Result<Option<ApiKeyCredentials>, Status> {
+  let row = sqlx::query!(
+    "UPDATE api_keys SET last_used_at = now() WHERE key_hash = $1 AND revoked_at IS NULL \
+     RETURNING user_id, scopes",
+    hash_api_key(api_key)
+  )
+  .fetch_optional(pool())
+  .await
+  .map_err(|err| {
+    error!("Error reading API key from database: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  Ok(row.map(|row| ApiKeyCredentials {
+    user_id: row.user_id,
+    scopes: parse_api_key_scopes(&row.scopes),
+  }))
+}
+
+pub async fn insert_api_key(
+  user_id: i32,
+  name: &str,
+  key_hash: &str,
+  key_prefix: &str,
+  scopes: &[ApiKeyScope],
+  max_api_keys_per_user: i64,
+) -> Result<ApiKeyInfo, Status> {
+  let active_key_count = sqlx::query_scalar!(
+    "SELECT COUNT(*) FROM api_keys WHERE user_id = $1 AND revoked_at IS NULL",
+    user_id
+  )
+  .fetch_one(pool())
+  .await
+  .map_err(|err| {
+    error!("Error counting API keys: {err}");
+    Status::internal("Internal DB error")
+  })?
+  .unwrap_or(0);
+  if active_key_count >= max_api_keys_per_user {
+    return Err(Status::resource_exhausted(format!(
+      "Can't have more than {max_api_keys_per_user} API keys; revoke an existing key first"
+    )));
+  }
+
+  let scope_names: Vec<String> = scopes
+    .iter()
+    .map(|scope| scope.as_str_name().to_owned())
+    .collect();
+  let row = sqlx::query!(
+    "INSERT INTO api_keys (user_id, name, key_hash, key_prefix, scopes) VALUES ($1, $2, $3, $4, \
+     $5) RETURNING id, created_at",
+    user_id,
+    name,
+    key_hash,
+    key_prefix,
+    &scope_names
+  )
+  .fetch_one(pool())
+  .await
+  .map_err(|err| {
+    error!("Error inserting API key: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  Ok(ApiKeyInfo {
+    id: row.id,
+    name: name.to_owned(),
+    key_prefix: key_prefix.to_owned(),
+    scopes: scopes.iter().map(|&scope| scope as i32).collect(),
+    created_at: row.created_at.and_utc().timestamp(),
+    last_used_at: None,
+  })
+}
+
+pub async fn get_user_api_keys(user_id: i32) -> sqlx::Result<Vec<ApiKeyInfo>> {
+  let rows = sqlx::query!(
+    "SELECT id, name, key_prefix, scopes, created_at, last_used_at FROM api_keys WHERE user_id = \
+     $1 AND revoked_at IS NULL ORDER BY created_at",
+    user_id
+  )
+  .fetch_all(pool())
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| ApiKeyInfo {
+        id: row.id,
+        name: row.name,
+        key_prefix: row.key_prefix,
+        scopes: parse_api_key_scopes(&row.scopes)
+          .into_iter()
+          .map(|scope| scope as i32)
+          .collect(),
+        created_at: row.created_at.and_utc().timestamp(),
+        last_used_at: row
+          .last_used_at
+          .map(|last_used_at| last_used_at.and_utc().timestamp()),
+      })
+      .collect(),
+  )
+}
+
+/// Revokes one of the user's API keys.  Returns `false` if the user has no active key with that ID.
+pub async fn revoke_api_key(user_id: i32, api_key_id: i32) -> sqlx::Result<bool> {
+  let res = sqlx::query!(
+    "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+    api_key_id,
+    user_id
+  )
+  .execute(pool())
+  .await?;
+
+  Ok(res.rows_affected() > 0)
+}
+
 pub async fn get_hashed_password(username: &str) -> Result<Option<(i32, String)>, Status> {
   let record = sqlx::query!(
-    "SELECT id, hashed_password FROM users WHERE username = $1",
+    "SELECT id, hashed_password FROM users WHERE lower(username) = lower($1)",
     username
   )
   .fetch_optional(pool())
@@ -96,7 +488,24 @@ pub async fn get_hashed_password(username: &str) -> Result<Option<(i32, String)>
     Status::internal("Internal DB error")
   })?;
 
-  Ok(record.map(|row| (row.id, row.hashed_password)))
+  // Guest accounts have no password and can't be logged into
+  Ok(record.and_then(|row| row.hashed_password.map(|hash| (row.id, hash))))
+}
+
+pub async fn update_hashed_password(user_id: i32, hashed_password: &str) -> Result<(), Status> {
+  sqlx::query!(
+    "UPDATE users SET hashed_password = $2 WHERE id = $1",
+    user_id,
+    hashed_password
+  )
+  .execute(pool())
+  .await
+  .map_err(|err| {
+    error!("Error updating hashed password: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  Ok(())
 }
 
 pub async fn insert_session_token(user_id: i32, session_token: &str) -> Result<(), Status> {
@@ -118,10 +527,9 @@ pub async fn insert_session_token(user_id: i32, session_token: &str) -> Result<(
 /// Adds a new user to the database with the provided username and password, returning the ID of the
 /// new user if successful.
 pub async fn insert_new_user(username: &str, password: &str) -> Result<i32, Status> {
-  let hashed_password = hash_password(password).map_err(|err| {
-    error!("Error hashing password: {err}");
-    Status::internal("Internal error")
-  })?;
+  validate_new_credentials(username, password)?;
+
+  let hashed_password = hash_password(password).await?;
 
   let user_id = sqlx::query!(
     "INSERT INTO users (username, hashed_password) VALUES ($1, $2) RETURNING id",
@@ -144,6 +552,31 @@ pub async fn insert_new_user(username: &str, password: &str) -> Result<i32, Stat
     Status::internal("Internal error registering user")
   })?;
 
+  insert_base(user_id).await?;
+
+  Ok(user_id)
+}
+
+/// Adds a new guest user with the provided username and no password, returning the ID of the new
+/// user if successful.
+pub async fn insert_guest_user(username: &str) -> Result<i32, Status> {
+  let user_id = sqlx::query_scalar!(
+    "INSERT INTO users (username, is_guest) VALUES ($1, true) RETURNING id",
+    username
+  )
+  .fetch_one(pool())
+  .await
+  .map_err(|err| {
+    error!("Error inserting new guest user: {err}");
+    Status::internal("Internal error creating guest account")
+  })?;
+
+  insert_base(user_id).await?;
+
+  Ok(user_id)
+}
+
+async fn insert_base(user_id: i32) -> Result<(), Status> {
   sqlx::query!(
     "INSERT INTO bases (user_id) VALUES ($1) ON CONFLICT DO NOTHING",
     user_id,
@@ -155,69 +588,1073 @@ pub async fn insert_new_user(username: &str, password: &str) -> Result<i32, Stat
     Status::internal("Internal DB error")
   })?;
 
-  Ok(user_id)
+  Ok(())
+}
+
+/// Sets the username and password of a guest account, converting it into a regular account.  All
+/// inventory and base progress stays attached to the same user ID.
+pub async fn claim_guest_account(
+  user_id: i32,
+  username: &str,
+  password: &str,
+) -> Result<UserAccountInfo, Status> {
+  validate_new_credentials(username, password)?;
+
+  let hashed_password = hash_password(password).await?;
+
+  let row = sqlx::query!(
+    "UPDATE users SET username = $2, hashed_password = $3, is_guest = false WHERE id = $1 AND \
+     is_guest RETURNING id, username, is_guest, prestige_level",
+    user_id,
+    username,
+    hashed_password
+  )
+  .fetch_optional(pool())
+  .await
+  .map_err(|err| {
+    if let Some(err) = err.as_database_error() {
+      if err.constraint().is_some() {
+        warn!("Tried to claim guest account with username that already exists: {username}");
+        return Status::already_exists("User already exists");
+      }
+    }
+
+    error!("Error claiming guest account: {err}");
+    Status::internal("Internal error claiming guest account")
+  })?
+  .ok_or_else(|| Status::failed_precondition("Account is not a guest account"))?;
+
+  Ok(UserAccountInfo {
+    id: row.id,
+    username: row.username,
+    is_guest: row.is_guest,
+    prestige_level: row.prestige_level as _,
+  })
 }
 
+/// Syncs the `items` table with the provided descriptors in a single transaction.  Items that are
+/// no longer in the list are deleted if nothing references them, and tombstoned otherwise.
 pub async fn insert_item_descriptors(items: &[ItemDescriptor]) -> Result<(), Status> {
-  for item in items {
+  let ids: Vec<i32> = items.iter().map(|item| item.id as i32).collect();
+  let names: Vec<String> = items.iter().map(|item| item.name.clone()).collect();
+  let display_names: Vec<String> = items.iter().map(|item| item.display_name.clone()).collect();
+  let descriptions: Vec<String> = items.iter().map(|item| item.description.clone()).collect();
+  let rarity_tiers: Vec<i16> = items.iter().map(|item| item.rarity_tier as i16).collect();
+  let image_urls: Vec<Option<String>> = items.iter().map(|item| item.image_url.clone()).collect();
+  let stack_sizes: Vec<Option<i32>> = items
+    .iter()
+    .map(|item| item.stack_size.map(|size| size as i32))
+    .collect();
+
+  let map_err = |err: sqlx::Error| {
+    error!("Error syncing item descriptors: {err}");
+    Status::internal("Internal DB error inserting items")
+  };
+
+  let mut txn = pool().begin().await.map_err(map_err)?;
+
+  sqlx::query!(
+    "INSERT INTO items (id, name, display_name, description, rarity_tier, image_url, stack_size) \
+     SELECT * FROM UNNEST($1::int4[], $2::text[], $3::text[], $4::text[], $5::int2[], $6::text[], \
+     $7::int4[]) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name, display_name = \
+     EXCLUDED.display_name, description = EXCLUDED.description, rarity_tier = \
+     EXCLUDED.rarity_tier, image_url = EXCLUDED.image_url, stack_size = EXCLUDED.stack_size, \
+     removed_at = NULL",
+    &ids,
+    &names,
+    &display_names,
+    &descriptions,
+    &rarity_tiers,
+    &image_urls as &[Option<String>],
+    &stack_sizes as &[Option<i32>],
+  )
+  .execute(&mut *txn)
+  .await
+  .map_err(map_err)?;
+
+  let deleted = sqlx::query!(
+    "DELETE FROM items WHERE id <> ALL($1::int4[]) AND NOT EXISTS (SELECT 1 FROM inventory inv \
+     WHERE inv.item_id = items.id) AND NOT EXISTS (SELECT 1 FROM inventory_stacks stack WHERE \
+     stack.item_id = items.id)",
+    &ids
+  )
+  .execute(&mut *txn)
+  .await
+  .map_err(map_err)?;
+  let tombstoned = sqlx::query!(
+    "UPDATE items SET removed_at = now() WHERE id <> ALL($1::int4[]) AND removed_at IS NULL",
+    &ids
+  )
+  .execute(&mut *txn)
+  .await
+  .map_err(map_err)?;
+
+  txn.commit().await.map_err(map_err)?;
+
+  if deleted.rows_affected() > 0 || tombstoned.rows_affected() > 0 {
+    warn!(
+      "Deleted {} and tombstoned {} items that are no longer defined",
+      deleted.rows_affected(),
+      tombstoned.rows_affected()
+    );
+  }
+
+  Ok(())
+}
+
+pub struct NewInventoryItem {
+  pub id: Uuid,
+  pub user_id: i32,
+  pub item_id: i32,
+  pub quality: f32,
+  pub value: f32,
+  pub modifiers: Option<serde_json::Value>,
+  pub reason: InventoryChangeReason,
+}
+
+/// Why items were added to or removed from an inventory, as recorded in the ledger.
+#[derive(Clone)]
+pub enum InventoryChangeReason {
+  Mined {
+    location_name: &'static str,
+  },
+  StorageUpgrade {
+    level: u32,
+  },
+  /// Items delivered for a quest, and the quest's rewards
+  Quest {
+    name: &'static str,
+  },
+  /// Everything in the inventory, which is cleared when prestiging
+  Prestige {
+    level: u32,
+  },
+  /// Individual items moved into their stack by `stack_inventory_items`
+  Restack,
+}
+
+impl InventoryChangeReason {
+  fn ledger_reason(&self) -> LedgerReason {
+    match self {
+      InventoryChangeReason::Mined { .. } => LedgerReason::Mined,
+      InventoryChangeReason::StorageUpgrade { .. } => LedgerReason::StorageUpgrade,
+      InventoryChangeReason::Quest { .. } => LedgerReason::Quest,
+      InventoryChangeReason::Prestige { .. } => LedgerReason::Prestige,
+      InventoryChangeReason::Restack => LedgerReason::Restack,
+    }
+  }
+
+  fn detail(&self) -> String {
+    match self {
+      InventoryChangeReason::Mined { location_name } => location_name.to_string(),
+      InventoryChangeReason::StorageUpgrade { level } => level.to_string(),
+      InventoryChangeReason::Quest { name } => name.to_string(),
+      InventoryChangeReason::Prestige { level } => level.to_string(),
+      InventoryChangeReason::Restack => String::new(),
+    }
+  }
+}
+
+/// A change to one item or stack in a user's inventory.  Removals have negative values.
+struct LedgerChange {
+  user_id: i32,
+  item_id: i32,
+  item_uuid: Uuid,
+  count: i32,
+  quality: f64,
+  value: f64,
+  reason: InventoryChangeReason,
+}
+
+/// Appends changes to the inventory ledger.  Must be called in the same transaction as the changes
+/// themselves.
+async fn insert_ledger_entries(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  changes: &[LedgerChange],
+) -> sqlx::Result<()> {
+  let user_ids: Vec<i32> = changes.iter().map(|change| change.user_id).collect();
+  let item_ids: Vec<i32> = changes.iter().map(|change| change.item_id).collect();
+  let item_uuids: Vec<Uuid> = changes.iter().map(|change| change.item_uuid).collect();
+  let counts: Vec<i32> = changes.iter().map(|change| change.count).collect();
+  let qualities: Vec<f64> = changes.iter().map(|change| change.quality).collect();
+  let values: Vec<f64> = changes.iter().map(|change| change.value).collect();
+  let reasons: Vec<String> = changes
+    .iter()
+    .map(|change| change.reason.ledger_reason().as_str_name().to_owned())
+    .collect();
+  let details: Vec<String> = changes
+    .iter()
+    .map(|change| change.reason.detail())
+    .collect();
+
+  sqlx::query!(
+    "INSERT INTO inventory_ledger (user_id, item_id, item_uuid, count_change, quality_change, \
+     value_change, reason, reason_detail) SELECT * FROM UNNEST($1::int4[], $2::int4[], \
+     $3::uuid[], $4::int4[], $5::float8[], $6::float8[], $7::text[], $8::text[])",
+    &user_ids,
+    &item_ids,
+    &item_uuids,
+    &counts,
+    &qualities,
+    &values,
+    &reasons,
+    &details,
+  )
+  .execute(&mut **txn)
+  .await?;
+  Ok(())
+}
+
+pub async fn get_user_history(
+  user_id: i32,
+  page_size: u32,
+  page_number: u32,
+) -> sqlx::Result<Vec<LedgerEntry>> {
+  let page_size = page_size.min(1000);
+  let rows = with_read_pool(|pool| {
     sqlx::query!(
-      "INSERT INTO items (id, name, description, rarity_tier) VALUES ($1, $2, $3, $4) ON CONFLICT \
-       (id) DO UPDATE SET name = $2, description = $3, rarity_tier = $4;",
-      item.id as i64,
-      item.name,
-      item.description,
-      item.rarity_tier as i64
+      "SELECT id, item_id, item_uuid, count_change, quality_change, value_change, reason, \
+       reason_detail, created_at FROM inventory_ledger WHERE user_id = $1 ORDER BY id DESC LIMIT \
+       $2 OFFSET $3",
+      user_id,
+      page_size as i64,
+      page_number as i64 * page_size as i64,
     )
-    .execute(pool())
-    .await
-    .map_err(|err| {
-      error!("Error inserting item {}: {err}", item.id);
-      Status::internal("Internal DB error inserting items")
-    })?;
-  }
+    .fetch_all(pool)
+  })
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| LedgerEntry {
+        id: row.id,
+        item_type_id: row.item_id,
+        item_uuid: row.item_uuid.to_string(),
+        count_change: row.count_change,
+        quality_change: row.quality_change as f32,
+        value_change: row.value_change as f32,
+        reason: LedgerReason::from_str_name(&row.reason)
+          .map(|reason| reason as i32)
+          .unwrap_or_default(),
+        reason_detail: row.reason_detail.unwrap_or_default(),
+        created_at: row.created_at.and_utc().timestamp(),
+      })
+      .collect(),
+  )
+}
+
+/// Inserts as many of `items` as fit in their users' inventories, in order.  Stackable items are
+/// added to the user's stack of that item.  Returns the UUID each item was stored under, which is
+/// its stack's for stackable items, or `None` if it didn't fit.
+pub async fn insert_inventory_items(items: &[NewInventoryItem]) -> sqlx::Result<Vec<Option<Uuid>>> {
+  let mut txn = pool().begin().await?;
+  let stored = insert_inventory_items_txn(&mut txn, items).await?;
+  txn.commit().await?;
+  Ok(stored)
+}
+
+/// Same as `insert_inventory_items`, but as part of an existing transaction.
+pub async fn insert_inventory_items_txn(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  items: &[NewInventoryItem],
+) -> sqlx::Result<Vec<Option<Uuid>>> {
+  let mut user_ids: Vec<i32> = items.iter().map(|item| item.user_id).collect();
+  user_ids.sort_unstable();
+  user_ids.dedup();
+
+  // Lock the users' counts for the rest of the transaction so that concurrent inserts can't
+  // overshoot capacity.  Users are ordered so that concurrent batches lock their rows in the same
+  // order.
+  sqlx::query!(
+    "INSERT INTO inventory_counts (user_id) SELECT * FROM UNNEST($1::int4[]) ON CONFLICT DO \
+     NOTHING",
+    &user_ids
+  )
+  .execute(&mut **txn)
+  .await?;
+  let rows = sqlx::query!(
+    "SELECT inventory_counts.user_id, inventory_counts.item_count, bases.storage_level AS \
+     \"storage_level?\" FROM inventory_counts LEFT JOIN bases ON bases.user_id = \
+     inventory_counts.user_id WHERE inventory_counts.user_id = ANY($1::int4[]) ORDER BY \
+     inventory_counts.user_id FOR UPDATE OF inventory_counts",
+    &user_ids
+  )
+  .fetch_all(&mut **txn)
+  .await?;
+  let mut available_space: FxHashMap<i32, i32> = rows
+    .into_iter()
+    .map(|row| {
+      let capacity = get_inventory_capacity(row.storage_level.unwrap_or(0));
+      (row.user_id, capacity - row.item_count)
+    })
+    .collect();
+  let mut stacks = lock_item_stacks(txn, &user_ids).await?;
+
+  let buckets = quality_histogram_buckets();
+  let mut used_slots: FxHashMap<i32, i32> = FxHashMap::default();
+  let mut histogram_deltas: FxHashMap<(i32, i32), HistogramDelta> = FxHashMap::default();
+  let mut updated_stacks = FxHashSet::default();
+  let mut unstacked_items = Vec::new();
+  let mut ledger_changes = Vec::new();
+  let mut stored_items = Vec::new();
+  let mut stored = Vec::with_capacity(items.len());
+  for item in items {
+    let Some(space) = available_space.get_mut(&item.user_id) else {
+      stored.push(None);
+      continue;
+    };
+
+    let mut item_uuid = item.id;
+    let needed_slots = match get_item_stack_size(item.item_id as _) {
+      Some(stack_size) => {
+        let stack = stacks
+          .entry((item.user_id, item.item_id))
+          .or_insert_with(|| ItemStack::new(item.item_id, buckets));
+        item_uuid = stack.id;
+        let needed_slots = stack_slots(stack.count + 1, Some(stack_size))
+          - stack_slots(stack.count, Some(stack_size));
+        if needed_slots <= *space {
+          stack.add(item.quality, item.value);
+          updated_stacks.insert((item.user_id, item.item_id));
+        }
+        needed_slots
+      },
+      None => {
+        if *space > 0 {
+          unstacked_items.push(item);
+        }
+        1
+      },
+    };
+
+    if needed_slots <= *space {
+      *space -= needed_slots;
+      *used_slots.entry(item.user_id).or_default() += needed_slots;
+      histogram_deltas
+        .entry((item.user_id, item.item_id))
+        .or_insert_with(|| HistogramDelta::new(buckets))
+        .add(item.quality, item.value);
+      ledger_changes.push(LedgerChange {
+        user_id: item.user_id,
+        item_id: item.item_id,
+        item_uuid,
+        count: 1,
+        quality: item.quality as f64,
+        value: item.value as f64,
+        reason: item.reason.clone(),
+      });
+      stored_items.push((item, item_uuid));
+      stored.push(Some(item_uuid));
+    } else {
+      stored.push(None);
+    }
+  }
+
+  let ids: Vec<Uuid> = unstacked_items.iter().map(|item| item.id).collect();
+  let item_user_ids: Vec<i32> = unstacked_items.iter().map(|item| item.user_id).collect();
+  let item_ids: Vec<i32> = unstacked_items.iter().map(|item| item.item_id).collect();
+  let qualities: Vec<f32> = unstacked_items.iter().map(|item| item.quality).collect();
+  let values: Vec<f32> = unstacked_items.iter().map(|item| item.value).collect();
+  let modifiers: Vec<Option<serde_json::Value>> = unstacked_items
+    .iter()
+    .map(|item| item.modifiers.clone())
+    .collect();
+
+  sqlx::query!(
+    "INSERT INTO inventory (id, user_id, item_id, quality, value, modifiers) SELECT * FROM \
+     UNNEST($6::uuid[], $1::int4[], $2::int4[], $3::float4[], $4::float4[], $5::jsonb[])",
+    &item_user_ids,
+    &item_ids,
+    &qualities,
+    &values,
+    &modifiers as &[Option<serde_json::Value>],
+    &ids,
+  )
+  .execute(&mut **txn)
+  .await?;
+
+  for key in updated_stacks {
+    save_item_stack(txn, key.0, &stacks[&key]).await?;
+  }
+  update_inventory_histograms(txn, &histogram_deltas).await?;
+  insert_ledger_entries(txn, &ledger_changes).await?;
+  record_mined_items(txn, &stored_items).await?;
+
+  let (slot_user_ids, slot_counts): (Vec<i32>, Vec<i32>) = used_slots.into_iter().unzip();
+  sqlx::query!(
+    "UPDATE inventory_counts SET item_count = item_count + used.slots FROM UNNEST($1::int4[], \
+     $2::int4[]) AS used(user_id, slots) WHERE inventory_counts.user_id = used.user_id",
+    &slot_user_ids,
+    &slot_counts
+  )
+  .execute(&mut **txn)
+  .await?;
+
+  Ok(stored)
+}
+
+/// Adds newly stored items that were mined to their users' lifetime stats.
+async fn record_mined_items(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  items: &[(&NewInventoryItem, Uuid)],
+) -> sqlx::Result<()> {
+  type Find<'a> = (&'a NewInventoryItem, Uuid, u32, &'static str);
+
+  let mut location_totals: FxHashMap<(i32, &'static str), (i64, f64)> = FxHashMap::default();
+  let mut rarest: FxHashMap<i32, Find> = FxHashMap::default();
+  let mut most_valuable: FxHashMap<i32, Find> = FxHashMap::default();
+  for &(item, item_uuid) in items {
+    let InventoryChangeReason::Mined { location_name } = item.reason else {
+      continue;
+    };
+    let totals = location_totals
+      .entry((item.user_id, location_name))
+      .or_default();
+    totals.0 += 1;
+    totals.1 += item.value as f64;
+
+    let find = (
+      item,
+      item_uuid,
+      get_item_rarity_tier(item.item_id as _),
+      location_name,
+    );
+    let best = rarest.entry(item.user_id).or_insert(find);
+    if (find.2, item.quality) > (best.2, best.0.quality) {
+      *best = find;
+    }
+    let best = most_valuable.entry(item.user_id).or_insert(find);
+    if item.value > best.0.value {
+      *best = find;
+    }
+  }
+  if location_totals.is_empty() {
+    return Ok(());
+  }
+
+  let mut user_ids = Vec::new();
+  let mut location_names = Vec::new();
+  let mut counts = Vec::new();
+  let mut values = Vec::new();
+  for ((user_id, location_name), (count, value)) in location_totals {
+    user_ids.push(user_id);
+    location_names.push(location_name.to_owned());
+    counts.push(count);
+    values.push(value);
+  }
+  sqlx::query!(
+    "INSERT INTO user_location_stats (user_id, location_name, total_items, total_value) SELECT * \
+     FROM UNNEST($1::int4[], $2::text[], $3::int8[], $4::float8[]) ON CONFLICT (user_id, \
+     location_name) DO UPDATE SET total_items = user_location_stats.total_items + \
+     EXCLUDED.total_items, total_value = user_location_stats.total_value + EXCLUDED.total_value",
+    &user_ids,
+    &location_names,
+    &counts,
+    &values,
+  )
+  .execute(&mut **txn)
+  .await?;
+
+  let finds: Vec<_> = rarest
+    .into_values()
+    .map(|find| (BestFindKind::Rarest, find))
+    .chain(
+      most_valuable
+        .into_values()
+        .map(|find| (BestFindKind::MostValuable, find)),
+    )
+    .collect();
+  let kinds: Vec<String> = finds
+    .iter()
+    .map(|(kind, _)| kind.as_str_name().to_owned())
+    .collect();
+  let user_ids: Vec<i32> = finds.iter().map(|(_, find)| find.0.user_id).collect();
+  let item_ids: Vec<i32> = finds.iter().map(|(_, find)| find.0.item_id).collect();
+  let item_uuids: Vec<Uuid> = finds.iter().map(|(_, find)| find.1).collect();
+  let rarity_tiers: Vec<i32> = finds.iter().map(|(_, find)| find.2 as i32).collect();
+  let qualities: Vec<f32> = finds.iter().map(|(_, find)| find.0.quality).collect();
+  let values: Vec<f32> = finds.iter().map(|(_, find)| find.0.value).collect();
+  let location_names: Vec<String> = finds.iter().map(|(_, find)| find.3.to_owned()).collect();
+  sqlx::query!(
+    "INSERT INTO user_best_finds (user_id, kind, item_id, item_uuid, rarity_tier, quality, value, \
+     location_name) SELECT * FROM UNNEST($1::int4[], $2::text[], $3::int4[], $4::uuid[], \
+     $5::int4[], $6::float4[], $7::float4[], $8::text[]) ON CONFLICT (user_id, kind) DO UPDATE \
+     SET item_id = EXCLUDED.item_id, item_uuid = EXCLUDED.item_uuid, rarity_tier = \
+     EXCLUDED.rarity_tier, quality = EXCLUDED.quality, value = EXCLUDED.value, location_name = \
+     EXCLUDED.location_name, found_at = now() WHERE CASE EXCLUDED.kind WHEN 'Rarest' THEN \
+     (EXCLUDED.rarity_tier, EXCLUDED.quality) > (user_best_finds.rarity_tier, \
+     user_best_finds.quality) ELSE EXCLUDED.value > user_best_finds.value END",
+    &user_ids,
+    &kinds,
+    &item_ids,
+    &item_uuids,
+    &rarity_tiers,
+    &qualities,
+    &values,
+    &location_names,
+  )
+  .execute(&mut **txn)
+  .await?;
+  Ok(())
+}
+
+/// Adds time spent mining at a location to the user's lifetime stats.
+pub async fn record_mining_time(
+  user_id: i32,
+  location_name: &str,
+  ticks: i64,
+  millis: i64,
+) -> sqlx::Result<()> {
+  sqlx::query!(
+    "INSERT INTO user_location_stats (user_id, location_name, total_ticks, mining_millis) VALUES \
+     ($1, $2, $3, $4) ON CONFLICT (user_id, location_name) DO UPDATE SET total_ticks = \
+     user_location_stats.total_ticks + EXCLUDED.total_ticks, mining_millis = \
+     user_location_stats.mining_millis + EXCLUDED.mining_millis",
+    user_id,
+    location_name,
+    ticks,
+    millis
+  )
+  .execute(pool())
+  .await?;
+  Ok(())
+}
+
+/// Total number of items the user has mined at each location they've mined at.
+pub async fn get_user_items_mined(user_id: i32) -> sqlx::Result<FxHashMap<String, i64>> {
+  let rows = sqlx::query!(
+    "SELECT location_name, total_items FROM user_location_stats WHERE user_id = $1",
+    user_id
+  )
+  .fetch_all(pool())
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| (row.location_name, row.total_items))
+      .collect(),
+  )
+}
+
+/// Returns when each of the user's unlocked achievements was unlocked, keyed by achievement ID.
+pub async fn get_user_achievements(user_id: i32) -> sqlx::Result<FxHashMap<u32, i64>> {
+  let rows = sqlx::query!(
+    "SELECT achievement_id, unlocked_at FROM user_achievements WHERE user_id = $1",
+    user_id
+  )
+  .fetch_all(pool())
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| {
+        (
+          row.achievement_id as u32,
+          row.unlocked_at.and_utc().timestamp(),
+        )
+      })
+      .collect(),
+  )
+}
+
+/// Marks achievements as unlocked for the user.  Returns the ones that weren't already unlocked,
+/// with when they were unlocked.
+pub async fn insert_user_achievements(
+  user_id: i32,
+  achievement_ids: &[i32],
+) -> sqlx::Result<Vec<(u32, i64)>> {
+  let rows = sqlx::query!(
+    "INSERT INTO user_achievements (user_id, achievement_id) SELECT $1, * FROM UNNEST($2::int4[]) \
+     ON CONFLICT DO NOTHING RETURNING achievement_id, unlocked_at",
+    user_id,
+    achievement_ids
+  )
+  .fetch_all(pool())
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| {
+        (
+          row.achievement_id as u32,
+          row.unlocked_at.and_utc().timestamp(),
+        )
+      })
+      .collect(),
+  )
+}
+
+/// Number of items the user has mined at each location since `since`, counted from the ledger.
+pub async fn get_user_items_mined_since(
+  user_id: i32,
+  since: NaiveDateTime,
+) -> sqlx::Result<FxHashMap<String, i64>> {
+  let rows = sqlx::query!(
+    "SELECT reason_detail AS \"location_name!\", SUM(count_change)::int8 AS \"count!\" FROM \
+     inventory_ledger WHERE user_id = $1 AND reason = $2 AND created_at >= $3 GROUP BY \
+     reason_detail",
+    user_id,
+    LedgerReason::Mined.as_str_name(),
+    since
+  )
+  .fetch_all(pool())
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| (row.location_name, row.count))
+      .collect(),
+  )
+}
+
+/// Total quality of each of `item_ids` in the user's inventory, not counting locked items.  Items
+/// the user has none of are left out.
+pub async fn get_user_unlocked_item_quality(
+  user_id: i32,
+  item_ids: &[i32],
+) -> sqlx::Result<FxHashMap<i32, f64>> {
+  let rows = sqlx::query!(
+    "SELECT item_id AS \"item_id!\", SUM(quality)::float8 AS \"total_quality!\" FROM (SELECT \
+     item_id, quality::float8 FROM inventory WHERE user_id = $1 AND item_id = ANY($2) AND NOT \
+     locked UNION ALL SELECT item_id, total_quality FROM inventory_stacks WHERE user_id = $1 AND \
+     item_id = ANY($2) AND NOT locked) items GROUP BY item_id",
+    user_id,
+    item_ids
+  )
+  .fetch_all(pool())
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| (row.item_id, row.total_quality))
+      .collect(),
+  )
+}
+
+/// Names of the quests offered on `day` that the user has completed.
+pub async fn get_user_completed_quests(user_id: i32, day: NaiveDate) -> sqlx::Result<Vec<String>> {
+  sqlx::query_scalar!(
+    "SELECT quest_name FROM user_quest_completions WHERE user_id = $1 AND day = $2",
+    user_id,
+    day
+  )
+  .fetch_all(pool())
+  .await
+}
+
+/// Marks a quest offered on `day` as completed by the user.  Returns false if it already was.
+/// Concurrent completions of the same quest wait for each other, so only one of them succeeds.
+pub async fn insert_quest_completion(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  user_id: i32,
+  day: NaiveDate,
+  quest_name: &str,
+) -> sqlx::Result<bool> {
+  let res = sqlx::query!(
+    "INSERT INTO user_quest_completions (user_id, day, quest_name) VALUES ($1, $2, $3) ON \
+     CONFLICT DO NOTHING",
+    user_id,
+    day,
+    quest_name
+  )
+  .execute(&mut **txn)
+  .await?;
+  Ok(res.rows_affected() > 0)
+}
+
+pub async fn get_user_stats(user_id: i32) -> sqlx::Result<UserStats> {
+  let locations = with_read_pool(|pool| {
+    sqlx::query!(
+      "SELECT location_name, total_ticks, mining_millis, total_items, total_value FROM \
+       user_location_stats WHERE user_id = $1 ORDER BY location_name",
+      user_id
+    )
+    .fetch_all(pool)
+  })
+  .await?;
+  let best_finds = with_read_pool(|pool| {
+    sqlx::query!(
+      "SELECT kind, item_id, item_uuid, quality, value, location_name, found_at FROM \
+       user_best_finds WHERE user_id = $1 ORDER BY kind",
+      user_id
+    )
+    .fetch_all(pool)
+  })
+  .await?;
+
+  Ok(UserStats {
+    locations: locations
+      .into_iter()
+      .map(|row| LocationStats {
+        location_name: row.location_name,
+        total_ticks: row.total_ticks as _,
+        mining_seconds: (row.mining_millis / 1000) as _,
+        total_items: row.total_items as _,
+        total_value: row.total_value,
+      })
+      .collect(),
+    best_finds: best_finds
+      .into_iter()
+      .filter_map(|row| {
+        Some(BestFind {
+          kind: BestFindKind::from_str_name(&row.kind)? as i32,
+          item: Some(Item {
+            item_type_id: row.item_id,
+            quality: row.quality,
+            value: row.value,
+            modifiers: Vec::new(),
+            item_uuid: row.item_uuid.to_string(),
+            stack_count: 0,
+            locked: false,
+          }),
+          location_name: row.location_name,
+          found_at: row.found_at.and_utc().timestamp(),
+        })
+      })
+      .collect(),
+  })
+}
+
+/// Locks a user's inventory count for the rest of the transaction, creating it if it doesn't
+/// exist.  Everything that changes a user's inventory takes this lock first.
+async fn lock_inventory_count(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  user_id: i32,
+) -> sqlx::Result<i32> {
+  sqlx::query!(
+    "INSERT INTO inventory_counts (user_id) VALUES ($1) ON CONFLICT DO NOTHING",
+    user_id
+  )
+  .execute(&mut **txn)
+  .await?;
+  sqlx::query_scalar!(
+    "SELECT item_count FROM inventory_counts WHERE user_id = $1 FOR UPDATE",
+    user_id
+  )
+  .fetch_one(&mut **txn)
+  .await
+}
+
+async fn update_inventory_count(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  user_id: i32,
+  delta: i32,
+) -> sqlx::Result<()> {
+  sqlx::query!(
+    "UPDATE inventory_counts SET item_count = item_count + $2 WHERE user_id = $1",
+    user_id,
+    delta
+  )
+  .execute(&mut **txn)
+  .await?;
+  Ok(())
+}
+
+/// Locks the item stacks of the given users for the rest of the transaction.  Returns them keyed
+/// by `(user_id, item_id)`.
+async fn lock_item_stacks(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  user_ids: &[i32],
+) -> sqlx::Result<FxHashMap<(i32, i32), ItemStack>> {
+  let rows = sqlx::query!(
+    "SELECT id, user_id, item_id, count, total_quality, total_value, quality_histogram, locked \
+     FROM inventory_stacks WHERE user_id = ANY($1::int4[]) ORDER BY user_id, item_id FOR UPDATE",
+    user_ids
+  )
+  .fetch_all(&mut **txn)
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| {
+        let stack = ItemStack {
+          id: row.id,
+          item_id: row.item_id,
+          count: row.count,
+          total_quality: row.total_quality,
+          total_value: row.total_value,
+          quality_histogram: row.quality_histogram,
+          locked: row.locked,
+        };
+        ((row.user_id, row.item_id), stack)
+      })
+      .collect(),
+  )
+}
+
+/// Writes a stack back to the database, deleting it if it's empty.
+async fn save_item_stack(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  user_id: i32,
+  stack: &ItemStack,
+) -> sqlx::Result<()> {
+  if stack.count <= 0 {
+    sqlx::query!("DELETE FROM inventory_stacks WHERE id = $1", stack.id)
+      .execute(&mut **txn)
+      .await?;
+    return Ok(());
+  }
+
+  sqlx::query!(
+    "INSERT INTO inventory_stacks (id, user_id, item_id, count, total_quality, total_value, \
+     quality_histogram) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (id) DO UPDATE SET count \
+     = EXCLUDED.count, total_quality = EXCLUDED.total_quality, total_value = \
+     EXCLUDED.total_value, quality_histogram = EXCLUDED.quality_histogram",
+    stack.id,
+    user_id,
+    stack.item_id,
+    stack.count,
+    stack.total_quality,
+    stack.total_value,
+    &stack.quality_histogram,
+  )
+  .execute(&mut **txn)
+  .await?;
+  Ok(())
+}
+
+/// Applies changes to users' per-item totals and quality histograms, deleting any that become
+/// empty.
+async fn update_inventory_histograms(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  deltas: &FxHashMap<(i32, i32), HistogramDelta>,
+) -> sqlx::Result<()> {
+  for (&(user_id, item_id), delta) in deltas {
+    sqlx::query!(
+      "INSERT INTO inventory_histograms (user_id, item_id, total_count, total_quality, \
+       total_value, quality_histogram) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (user_id, \
+       item_id) DO UPDATE SET total_count = inventory_histograms.total_count + \
+       EXCLUDED.total_count, total_quality = inventory_histograms.total_quality + \
+       EXCLUDED.total_quality, total_value = inventory_histograms.total_value + \
+       EXCLUDED.total_value, quality_histogram = ARRAY(SELECT a + b FROM \
+       UNNEST(inventory_histograms.quality_histogram, EXCLUDED.quality_histogram) WITH ORDINALITY \
+       AS t(a, b, ix) ORDER BY ix)",
+      user_id,
+      item_id,
+      delta.count,
+      delta.total_quality,
+      delta.total_value,
+      &delta.quality_histogram,
+    )
+    .execute(&mut **txn)
+    .await?;
+  }
+
+  let user_ids: Vec<i32> = deltas.keys().map(|&(user_id, _)| user_id).collect();
+  sqlx::query!(
+    "DELETE FROM inventory_histograms WHERE user_id = ANY($1::int4[]) AND total_count <= 0",
+    &user_ids
+  )
+  .execute(&mut **txn)
+  .await?;
+
+  Ok(())
+}
+
+/// Moves individual inventory rows of items that are stackable into their owners' stacks.  Needed
+/// when an item is made stackable after users already have some of it.  Locked and equipped items
+/// are left as they are so they stay that way, and so are items whose stack is locked so that they
+/// don't become locked.
+pub async fn stack_inventory_items() -> sqlx::Result<()> {
+  let stackable_item_ids: Vec<i32> = item_descriptors()
+    .iter()
+    .filter(|item| item.stack_size.is_some())
+    .map(|item| item.id as i32)
+    .collect();
+  let user_ids = sqlx::query_scalar!(
+    "SELECT DISTINCT user_id FROM inventory i WHERE item_id = ANY($1::int4[]) AND NOT locked AND \
+     NOT EXISTS (SELECT 1 FROM inventory_stacks s WHERE s.user_id = i.user_id AND s.item_id = \
+     i.item_id AND s.locked) AND NOT EXISTS (SELECT 1 FROM user_equipment e WHERE e.item_uuid = \
+     i.id)",
+    &stackable_item_ids
+  )
+  .fetch_all(pool())
+  .await?;
+
+  for &user_id in &user_ids {
+    let mut txn = pool().begin().await?;
+    lock_inventory_count(&mut txn, user_id).await?;
+    let mut stacks = lock_item_stacks(&mut txn, &[user_id]).await?;
+    let rows = sqlx::query!(
+      "DELETE FROM inventory i WHERE user_id = $1 AND item_id = ANY($2::int4[]) AND NOT locked \
+       AND NOT EXISTS (SELECT 1 FROM inventory_stacks s WHERE s.user_id = i.user_id AND s.item_id \
+       = i.item_id AND s.locked) AND NOT EXISTS (SELECT 1 FROM user_equipment e WHERE e.item_uuid \
+       = i.id) RETURNING id, item_id, quality, value",
+      user_id,
+      &stackable_item_ids
+    )
+    .fetch_all(&mut *txn)
+    .await?;
+
+    let mut slot_delta = -(rows.len() as i32);
+    let mut counts_before: FxHashMap<i32, i32> = FxHashMap::default();
+    let mut ledger_changes = Vec::with_capacity(rows.len() * 2);
+    for row in &rows {
+      let stack = stacks
+        .entry((user_id, row.item_id))
+        .or_insert_with(|| ItemStack::new(row.item_id, quality_histogram_buckets()));
+      counts_before.entry(row.item_id).or_insert(stack.count);
+      stack.add(row.quality, row.value);
+
+      ledger_changes.push(LedgerChange {
+        user_id,
+        item_id: row.item_id,
+        item_uuid: row.id,
+        count: -1,
+        quality: -row.quality as f64,
+        value: -row.value as f64,
+        reason: InventoryChangeReason::Restack,
+      });
+      ledger_changes.push(LedgerChange {
+        user_id,
+        item_id: row.item_id,
+        item_uuid: stack.id,
+        count: 1,
+        quality: row.quality as f64,
+        value: row.value as f64,
+        reason: InventoryChangeReason::Restack,
+      });
+    }
+    for (item_id, count_before) in counts_before {
+      let stack = &stacks[&(user_id, item_id)];
+      let stack_size = get_item_stack_size(item_id as _);
+      slot_delta += stack_slots(stack.count, stack_size) - stack_slots(count_before, stack_size);
+      save_item_stack(&mut txn, user_id, stack).await?;
+    }
+    update_inventory_count(&mut txn, user_id, slot_delta).await?;
+    insert_ledger_entries(&mut txn, &ledger_changes).await?;
+
+    txn.commit().await?;
+  }
+
+  if !user_ids.is_empty() {
+    info!(
+      "Moved stackable items into stacks for {} users",
+      user_ids.len()
+    );
+  }
+
+  Ok(())
+}
+
+/// Recomputes every user's row in `inventory_counts` from `inventory` and `inventory_stacks`.
+/// Writes to the inventory are blocked while this runs.
+pub async fn recompute_inventory_counts() -> sqlx::Result<u64> {
+  let mut txn = pool().begin().await?;
+  sqlx::query!("LOCK TABLE inventory, inventory_stacks IN SHARE MODE")
+    .execute(&mut *txn)
+    .await?;
+  let res = sqlx::query!(
+    "INSERT INTO inventory_counts (user_id, item_count) SELECT users.id, (SELECT COUNT(*) FROM \
+     inventory WHERE inventory.user_id = users.id) + (SELECT \
+     COALESCE(SUM(CEIL(stack.count::float8 / COALESCE(items.stack_size, 1))), 0) FROM \
+     inventory_stacks stack JOIN items ON items.id = stack.item_id WHERE stack.user_id = \
+     users.id)::int8 FROM users ON CONFLICT (user_id) DO UPDATE SET item_count = \
+     EXCLUDED.item_count WHERE inventory_counts.item_count <> EXCLUDED.item_count"
+  )
+  .execute(&mut *txn)
+  .await?;
+  txn.commit().await?;
+
+  Ok(res.rows_affected())
+}
+
+/// Rebuilds every user's row in `inventory_histograms` from `inventory` and `inventory_stacks`,
+/// first moving any stack histograms with a different number of buckets into the configured
+/// number.  Writes to the inventory are blocked while this runs.
+pub async fn rebuild_quality_histograms() -> sqlx::Result<()> {
+  let buckets = quality_histogram_buckets();
+  let mut txn = pool().begin().await?;
+  sqlx::query!("LOCK TABLE inventory, inventory_stacks IN SHARE MODE")
+    .execute(&mut *txn)
+    .await?;
+
+  let stacks = sqlx::query!(
+    "SELECT id, quality_histogram FROM inventory_stacks WHERE cardinality(quality_histogram) <> $1",
+    buckets as i32
+  )
+  .fetch_all(&mut *txn)
+  .await?;
+  for stack in &stacks {
+    sqlx::query!(
+      "UPDATE inventory_stacks SET quality_histogram = $2 WHERE id = $1",
+      stack.id,
+      &rebucket_histogram(&stack.quality_histogram, buckets)
+    )
+    .execute(&mut *txn)
+    .await?;
+  }
+
+  sqlx::query!("DELETE FROM inventory_histograms")
+    .execute(&mut *txn)
+    .await?;
+  sqlx::query!(
+    "WITH entries (user_id, item_id, bucket_ix, count, quality, value) AS (SELECT user_id, \
+     item_id, LEAST(FLOOR(quality * $1::int4)::int4, $1::int4 - 1), 1, quality::float8, \
+     value::float8 FROM inventory UNION ALL SELECT stack.user_id, stack.item_id, (bucket.ix - \
+     1)::int, bucket.count, 0, 0 FROM inventory_stacks stack, UNNEST(stack.quality_histogram) \
+     WITH ORDINALITY AS bucket(count, ix) UNION ALL SELECT user_id, item_id, 0, 0, total_quality, \
+     total_value FROM inventory_stacks), buckets AS (SELECT user_id, item_id, bucket_ix, \
+     SUM(count) AS count, SUM(quality) AS quality, SUM(value) AS value FROM entries GROUP BY \
+     user_id, item_id, bucket_ix) INSERT INTO inventory_histograms (user_id, item_id, \
+     total_count, total_quality, total_value, quality_histogram) SELECT item.user_id, \
+     item.item_id, COALESCE(SUM(buckets.count), 0), COALESCE(SUM(buckets.quality), 0), \
+     COALESCE(SUM(buckets.value), 0), array_agg(COALESCE(buckets.count, 0)::int ORDER BY \
+     ix.bucket_ix) FROM (SELECT DISTINCT user_id, item_id FROM buckets) item CROSS JOIN \
+     generate_series(0, $1::int4 - 1) AS ix(bucket_ix) LEFT JOIN buckets ON buckets.user_id = \
+     item.user_id AND buckets.item_id = item.item_id AND buckets.bucket_ix = ix.bucket_ix GROUP \
+     BY item.user_id, item.item_id",
+    buckets as i32
+  )
+  .execute(&mut *txn)
+  .await?;
 
-  Ok(())
-}
+  txn.commit().await?;
+  info!("Rebuilt inventory quality histograms with {buckets} buckets");
 
-pub struct NewInventoryItem {
-  pub user_id: i32,
-  pub item_id: i32,
-  pub quality: f32,
-  pub value: f32,
-  pub modifiers: Option<serde_json::Value>,
+  Ok(())
 }
 
-pub async fn insert_inventory_items(items: &[NewInventoryItem]) -> sqlx::Result<PgQueryResult> {
-  let ids: Vec<Uuid> = items.iter().map(|_| Uuid::new_v4()).collect();
-  let user_ids: Vec<i32> = items.iter().map(|item| item.user_id).collect();
-  let item_ids: Vec<i32> = items.iter().map(|item| item.item_id).collect();
-  let qualities: Vec<f32> = items.iter().map(|item| item.quality).collect();
-  let values: Vec<f32> = items.iter().map(|item| item.value).collect();
-  let modifiers: Vec<Option<serde_json::Value>> =
-    items.iter().map(|item| item.modifiers.clone()).collect();
-
-  sqlx::query!(
-    "INSERT INTO inventory (id, user_id, item_id, quality, value, modifiers) SELECT * FROM \
-     UNNEST($6::uuid[], $1::int4[], $2::int4[], $3::float4[], $4::float4[], $5::jsonb[])",
-    &user_ids,
-    &item_ids,
-    &qualities,
-    &values,
-    &modifiers as &[Option<serde_json::Value>],
-    &ids,
+/// Whether any stored quality histograms have a different number of buckets than configured.
+async fn quality_histograms_outdated() -> sqlx::Result<bool> {
+  sqlx::query_scalar!(
+    "SELECT EXISTS (SELECT 1 FROM inventory_histograms WHERE cardinality(quality_histogram) <> \
+     $1) OR EXISTS (SELECT 1 FROM inventory_stacks WHERE cardinality(quality_histogram) <> $1) AS \
+     \"outdated!\"",
+    quality_histogram_buckets() as i32
   )
-  .execute(pool())
+  .fetch_one(pool())
   .await
 }
 
 pub async fn get_user_account(user_id: i32) -> sqlx::Result<Option<UserAccountInfo>> {
-  sqlx::query_as!(
-    UserAccountInfo,
-    "SELECT id, username FROM users WHERE id = $1",
+  let row = sqlx::query!(
+    "SELECT id, username, is_guest, prestige_level FROM users WHERE id = $1",
     user_id
   )
   .fetch_optional(pool())
-  .await
+  .await?;
+
+  Ok(row.map(|row| UserAccountInfo {
+    id: row.id,
+    username: row.username,
+    is_guest: row.is_guest,
+    prestige_level: row.prestige_level as _,
+  }))
+}
+
+pub async fn get_user_prestige_level(user_id: i32) -> sqlx::Result<u32> {
+  sqlx::query_scalar!("SELECT prestige_level FROM users WHERE id = $1", user_id)
+    .fetch_optional(pool())
+    .await
+    .map(|level| level.unwrap_or(0) as u32)
 }
 
 #[derive(FromRow)]
@@ -227,6 +1664,27 @@ pub struct DbItem {
   quality: f32,
   value: f32,
   modifiers: Option<serde_json::Value>,
+  /// Number of items if this is a stack, else 0
+  stack_count: i32,
+  locked: bool,
+}
+
+fn db_item_to_proto(item: DbItem) -> Result<Item, serde_json::Error> {
+  let modifiers = item
+    .modifiers
+    .into_iter()
+    .map(serde_json::from_value)
+    .collect::<Result<_, _>>()?;
+
+  Ok(Item {
+    item_type_id: item.item_id,
+    quality: item.quality,
+    value: item.value,
+    modifiers,
+    item_uuid: item.id.to_string(),
+    stack_count: item.stack_count as _,
+    locked: item.locked,
+  })
 }
 
 pub(crate) async fn get_user_inventory(
@@ -247,15 +1705,22 @@ pub(crate) async fn get_user_inventory(
   };
 
   let timer = crate::metrics::db::get_user_inventory_duration().start_timer();
-  let items: Vec<DbItem> = sqlx::query_as(&format!(
-    "SELECT inv.id, inv.item_id, inv.quality, inv.value, inv.modifiers FROM inventory inv JOIN \
-     items i ON inv.item_id = i.id WHERE inv.user_id = $1 ORDER BY {sort_column} {sort_direction} \
-     LIMIT $2 OFFSET $3"
-  ))
-  .bind(user_id)
-  .bind(page_size.clamp(0, 1000) as i32)
-  .bind((page_number * page_size) as i32)
-  .fetch_all(pool())
+  // Stacks are listed alongside individual items, with their average quality and total value
+  let query = format!(
+    "SELECT inv.id, inv.item_id, inv.quality, inv.value, inv.modifiers, inv.stack_count, \
+     inv.locked FROM (SELECT id, item_id, quality, value, modifiers, created_at, 0 AS \
+     stack_count, locked FROM inventory WHERE user_id = $1 UNION ALL SELECT id, item_id, \
+     (total_quality / count)::float4, total_value::float4, NULL, created_at, count, locked FROM \
+     inventory_stacks WHERE user_id = $1) inv JOIN items i ON inv.item_id = i.id ORDER BY \
+     {sort_column} {sort_direction} LIMIT $2 OFFSET $3"
+  );
+  let items: Vec<DbItem> = with_read_pool(|pool| {
+    sqlx::query_as(&query)
+      .bind(user_id)
+      .bind(page_size.clamp(0, 1000) as i32)
+      .bind((page_number * page_size) as i32)
+      .fetch_all(pool)
+  })
   .await
   .map_err(|err| {
     error!("Error reading user inventory from database: {err}");
@@ -263,115 +1728,60 @@ pub(crate) async fn get_user_inventory(
   })?;
   timer.stop_and_record();
 
-  Ok(
-    items
-      .into_iter()
-      .map(|item| -> Result<_, Status> {
-        let modifiers = item
-          .modifiers
-          .into_iter()
-          .map(|modifier| serde_json::from_value(modifier))
-          .collect::<Result<_, _>>()
-          .map_err(|err| {
-            error!("Found item with un-parseable modifiers in DB: {err}");
-            Status::internal("Internal DB error fetching inventory")
-          })?;
-
-        Ok(Item {
-          item_type_id: item.item_id,
-          quality: item.quality,
-          value: item.value,
-          modifiers,
-          item_uuid: item.id.to_string(),
-        })
-      })
-      .collect::<Result<_, _>>()?,
-  )
+  items
+    .into_iter()
+    .map(db_item_to_proto)
+    .collect::<Result<_, _>>()
+    .map_err(|err| {
+      error!("Found item with un-parseable modifiers in DB: {err}");
+      Status::internal("Internal DB error fetching inventory")
+    })
 }
 
 pub async fn get_user_aggregated_inventory(user_id: i32) -> sqlx::Result<AggregatedInventory> {
-  // We want total item count for each item ID in the user's inventory along with histogram buckets
-  // for the quality distribution of each item.
-  //
-  // Quality is hard-capped from [0,1], and we used 32 fixed buckets for now.
   let timer = crate::metrics::db::get_user_aggregated_inventory_duration().start_timer();
-  let rows = sqlx::query!(
-    "SELECT item_id, COUNT(*) as total_count, SUM(quality) as total_quality, SUM(value) as \
-     total_value, width_bucket(quality, 0, 1, 32) AS quality_bucket_ix FROM inventory WHERE \
-     user_id = $1 GROUP BY item_id, quality_bucket_ix",
-    user_id
-  )
-  .fetch_all(pool())
+  let rows = with_read_pool(|pool| {
+    sqlx::query!(
+      "SELECT item_id, total_count, total_quality, total_value, quality_histogram FROM \
+       inventory_histograms WHERE user_id = $1 ORDER BY total_count DESC",
+      user_id
+    )
+    .fetch_all(pool)
+  })
   .await?;
   timer.stop_and_record();
 
-  struct QualityBucket {
-    bucket_ix: u32,
-    total_count: u32,
-    total_quality: f32,
-    total_value: f32,
-  }
-
-  let mut counts_by_item_id: FxHashMap<i32, Vec<QualityBucket>> = FxHashMap::default();
-  for row in rows {
-    let item_id = row.item_id as i32;
-    let quality_bucket_ix = row.quality_bucket_ix.unwrap_or(0) as u32;
-    let total_count = row.total_count.unwrap_or(0);
-    let total_quality = row.total_quality.unwrap_or(0.);
-    let total_value = row.total_value.unwrap_or(0.);
-
-    counts_by_item_id
-      .entry(item_id)
-      .or_insert_with(Vec::new)
-      .push(QualityBucket {
-        bucket_ix: quality_bucket_ix,
-        total_count: total_count as _,
-        total_quality,
-        total_value,
-      });
-  }
-
-  for bucket in counts_by_item_id.values_mut() {
-    for i in 1..=32 {
-      if !bucket.iter().any(|b| b.bucket_ix == i) {
-        bucket.push(QualityBucket {
-          bucket_ix: i,
-          total_count: 0,
-          total_quality: 0.,
-          total_value: 0.,
-        });
-      }
-    }
-    bucket.sort_unstable_by_key(|b| b.bucket_ix);
-  }
-
-  let mut item_counts: Vec<_> = counts_by_item_id
+  let item_counts = rows
     .into_iter()
-    .map(|(item_id, buckets)| AggregatedItemCount {
-      item_id: item_id as _,
-      total_count: buckets.iter().map(|b| b.total_count).sum(),
-      total_quality: buckets.iter().map(|b| b.total_quality).sum(),
-      total_value: buckets.iter().map(|b| b.total_value).sum(),
+    .map(|row| AggregatedItemCount {
+      item_id: row.item_id as _,
+      total_count: row.total_count as _,
+      total_quality: row.total_quality as _,
+      total_value: row.total_value as _,
       quality_histogram: Some(ItemQualityHistogram {
-        buckets: buckets
+        buckets: row
+          .quality_histogram
           .into_iter()
-          .map(|bucket| bucket.total_count)
+          .map(|count| count as _)
           .collect(),
       }),
     })
     .collect();
-  item_counts.sort_unstable_by_key(|count| Reverse(count.total_count));
 
   Ok(AggregatedInventory { item_counts })
 }
 
 pub async fn get_hiscores() -> sqlx::Result<Vec<HiscoreEntry>> {
   let timer = crate::metrics::db::get_hiscores_duration().start_timer();
-  let rows = sqlx::query!(
-    "SELECT u.username, SUM(inv.value) AS total_value FROM inventory inv INNER JOIN users u ON \
-     inv.user_id = u.id GROUP BY u.username ORDER BY total_value DESC LIMIT 100"
-  )
-  .fetch_all(pool())
+  let rows = with_read_pool(|pool| {
+    sqlx::query!(
+      "SELECT u.username, u.prestige_level, SUM(inv.value)::float4 AS total_value FROM (SELECT \
+       user_id, value::float8 FROM inventory UNION ALL SELECT user_id, total_value FROM \
+       inventory_stacks) inv INNER JOIN users u ON inv.user_id = u.id WHERE NOT u.is_guest GROUP \
+       BY u.username, u.prestige_level ORDER BY total_value DESC LIMIT 100"
+    )
+    .fetch_all(pool)
+  })
   .await?;
   timer.stop_and_record();
 
@@ -381,18 +1791,165 @@ pub async fn get_hiscores() -> sqlx::Result<Vec<HiscoreEntry>> {
       .map(|row| HiscoreEntry {
         username: row.username,
         total_value: row.total_value.unwrap_or_default(),
+        prestige_level: row.prestige_level as _,
       })
       .collect(),
   )
 }
 
+/// Number of entries in the user's inventory listing, where each stack counts as one entry.
+pub async fn get_user_inventory_entry_count(user_id: i32) -> sqlx::Result<i64> {
+  with_read_pool(|pool| {
+    sqlx::query_scalar!(
+      "SELECT (SELECT COALESCE(SUM(total_count), 0) FROM inventory_histograms WHERE user_id = $1) \
+       - (SELECT COALESCE(SUM(count - 1), 0) FROM inventory_stacks WHERE user_id = $1) AS \
+       \"count!\"",
+      user_id
+    )
+    .fetch_one(pool)
+  })
+  .await
+}
+
 pub async fn get_user_inventory_count(user_id: i32) -> sqlx::Result<Option<i64>> {
   let timer = crate::metrics::db::get_user_inventory_count_duration().start_timer();
-  let count = sqlx::query_scalar!("SELECT COUNT(*) FROM inventory WHERE user_id = $1", user_id)
-    .fetch_one(pool())
-    .await?;
+  let count = sqlx::query_scalar!(
+    "SELECT item_count FROM inventory_counts WHERE user_id = $1",
+    user_id
+  )
+  .fetch_optional(pool())
+  .await?;
   timer.stop_and_record();
-  Ok(count)
+  Ok(count.map(i64::from))
+}
+
+pub async fn get_profile_settings(user_id: i32) -> sqlx::Result<ProfileSettings> {
+  let row = sqlx::query!(
+    "SELECT hide_inventory, showcase_item_uuids FROM user_profiles WHERE user_id = $1",
+    user_id
+  )
+  .fetch_optional(pool())
+  .await?;
+
+  Ok(
+    row
+      .map(|row| ProfileSettings {
+        hide_inventory: row.hide_inventory,
+        showcase_item_uuids: row
+          .showcase_item_uuids
+          .iter()
+          .map(Uuid::to_string)
+          .collect(),
+      })
+      .unwrap_or_default(),
+  )
+}
+
+/// Returns false without changing anything if any of the showcase items aren't in the user's
+/// inventory.  `showcase_item_uuids` must not contain duplicates.
+pub async fn set_profile_settings(
+  user_id: i32,
+  hide_inventory: bool,
+  showcase_item_uuids: &[Uuid],
+) -> sqlx::Result<bool> {
+  let found = sqlx::query_scalar!(
+    "SELECT COUNT(*) AS \"count!\" FROM (SELECT id FROM inventory WHERE user_id = $1 AND id = \
+     ANY($2::uuid[]) UNION ALL SELECT id FROM inventory_stacks WHERE user_id = $1 AND id = \
+     ANY($2::uuid[])) items",
+    user_id,
+    showcase_item_uuids
+  )
+  .fetch_one(pool())
+  .await?;
+  if found != showcase_item_uuids.len() as i64 {
+    return Ok(false);
+  }
+
+  sqlx::query!(
+    "INSERT INTO user_profiles (user_id, hide_inventory, showcase_item_uuids) VALUES ($1, $2, $3) \
+     ON CONFLICT (user_id) DO UPDATE SET hide_inventory = EXCLUDED.hide_inventory, \
+     showcase_item_uuids = EXCLUDED.showcase_item_uuids",
+    user_id,
+    hide_inventory,
+    showcase_item_uuids
+  )
+  .execute(pool())
+  .await?;
+  Ok(true)
+}
+
+/// Builds the public profile of a non-guest user, leaving out the inventory details if they've
+/// hidden them.  Locations are left for the caller to fill in.
+pub async fn get_public_profile(username: &str) -> Result<Option<PlayerProfile>, Status> {
+  let map_err = |err: sqlx::Error| {
+    error!("Error reading profile from database: {err}");
+    Status::internal("Internal DB error fetching profile")
+  };
+  let Some(user) = with_read_pool(|pool| {
+    sqlx::query!(
+      "SELECT u.id, u.username, COALESCE(b.storage_level, 0) AS \"storage_level!\", \
+       COALESCE(p.hide_inventory, false) AS \"hide_inventory!\", COALESCE(p.showcase_item_uuids, \
+       '{}') AS \"showcase_item_uuids!\" FROM users u LEFT JOIN bases b ON b.user_id = u.id LEFT \
+       JOIN user_profiles p ON p.user_id = u.id WHERE lower(u.username) = lower($1) AND NOT \
+       u.is_guest",
+      username
+    )
+    .fetch_optional(pool)
+  })
+  .await
+  .map_err(map_err)?
+  else {
+    return Ok(None);
+  };
+
+  let mut profile = PlayerProfile {
+    username: user.username,
+    storage_level: user.storage_level as _,
+    inventory_hidden: user.hide_inventory,
+    showcase_items: Vec::new(),
+    total_value: 0.,
+    stats: Some(get_user_stats(user.id).await.map_err(map_err)?),
+  };
+  if user.hide_inventory {
+    return Ok(Some(profile));
+  }
+
+  profile.total_value = with_read_pool(|pool| {
+    sqlx::query_scalar!(
+      "SELECT SUM(value)::float4 FROM (SELECT value::float8 FROM inventory WHERE user_id = $1 \
+       UNION ALL SELECT total_value FROM inventory_stacks WHERE user_id = $1) inv",
+      user.id
+    )
+    .fetch_one(pool)
+  })
+  .await
+  .map_err(map_err)?
+  .unwrap_or_default();
+
+  let showcase_items: Vec<DbItem> = with_read_pool(|pool| {
+    sqlx::query_as(
+      "SELECT id, item_id, quality, value, modifiers, stack_count, locked FROM (SELECT id, \
+       item_id, quality, value, modifiers, 0 AS stack_count, locked FROM inventory WHERE user_id \
+       = $1 AND id = ANY($2) UNION ALL SELECT id, item_id, (total_quality / count)::float4, \
+       total_value::float4, NULL, count, locked FROM inventory_stacks WHERE user_id = $1 AND id = \
+       ANY($2)) inv ORDER BY array_position($2, inv.id)",
+    )
+    .bind(user.id)
+    .bind(&user.showcase_item_uuids)
+    .fetch_all(pool)
+  })
+  .await
+  .map_err(map_err)?;
+  profile.showcase_items = showcase_items
+    .into_iter()
+    .map(db_item_to_proto)
+    .collect::<Result<_, _>>()
+    .map_err(|err| {
+      error!("Found item with un-parseable modifiers in DB: {err}");
+      Status::internal("Internal DB error fetching profile")
+    })?;
+
+  Ok(Some(profile))
 }
 
 pub async fn get_user_storage_upgrade_level(user_id: i32) -> sqlx::Result<i32> {
@@ -409,74 +1966,370 @@ pub async fn get_available_inventory_space(user_id: i32) -> sqlx::Result<i32> {
   let item_count = get_user_inventory_count(user_id).await?.unwrap_or(0);
 
   let inventory_upgrade_level = get_user_storage_upgrade_level(user_id).await?;
-  let inventory_capacity =
-    BASE_INVENTORY_SIZE as i32 + inventory_upgrade_level * INVENTORY_CAPACITY_PER_UPGRADE as i32;
 
-  Ok(inventory_capacity - item_count as i32)
+  Ok(get_inventory_capacity(inventory_upgrade_level) - item_count as i32)
+}
+
+/// Items the user has equipped, with the slot each is in.
+pub async fn get_user_equipment(user_id: i32) -> Result<Vec<EquippedItem>, Status> {
+  let rows = sqlx::query!(
+    "SELECT e.slot, i.id, i.item_id, i.quality, i.value, i.modifiers, i.locked FROM \
+     user_equipment e INNER JOIN inventory i ON i.id = e.item_uuid WHERE e.user_id = $1",
+    user_id
+  )
+  .fetch_all(pool())
+  .await
+  .map_err(|err| {
+    error!("Error reading equipment from database: {err}");
+    Status::internal("Internal DB error fetching equipment")
+  })?;
+
+  rows
+    .into_iter()
+    .map(|row| {
+      let item = db_item_to_proto(DbItem {
+        id: row.id,
+        item_id: row.item_id,
+        quality: row.quality,
+        value: row.value,
+        modifiers: row.modifiers,
+        stack_count: 0,
+        locked: row.locked,
+      })?;
+      Ok(EquippedItem {
+        slot: EquipmentSlot::from_str_name(&row.slot)
+          .map(|slot| slot as i32)
+          .unwrap_or_default(),
+        item: Some(item),
+      })
+    })
+    .collect::<Result<_, serde_json::Error>>()
+    .map_err(|err| {
+      error!("Found item with un-parseable modifiers in DB: {err}");
+      Status::internal("Internal DB error fetching equipment")
+    })
+}
+
+/// Returns the item type of an individual item in the user's inventory, or `None` if they don't
+/// have it.  Stacks aren't included.
+pub async fn get_user_item_type(user_id: i32, id: Uuid) -> sqlx::Result<Option<i32>> {
+  sqlx::query_scalar!(
+    "SELECT item_id FROM inventory WHERE id = $1 AND user_id = $2",
+    id,
+    user_id
+  )
+  .fetch_optional(pool())
+  .await
+}
+
+/// Puts an item from the user's inventory into an equipment slot, replacing whatever was there.
+/// Returns false if the user doesn't have the item.
+pub async fn set_user_equipment(
+  user_id: i32,
+  slot: EquipmentSlot,
+  item_uuid: Uuid,
+) -> sqlx::Result<bool> {
+  let res = sqlx::query!(
+    "INSERT INTO user_equipment (user_id, slot, item_uuid) SELECT $1, $2, id FROM inventory WHERE \
+     id = $3 AND user_id = $1 ON CONFLICT (user_id, slot) DO UPDATE SET item_uuid = \
+     EXCLUDED.item_uuid, equipped_at = now()",
+    user_id,
+    slot.as_str_name(),
+    item_uuid
+  )
+  .execute(pool())
+  .await?;
+  Ok(res.rows_affected() > 0)
+}
+
+/// Empties an equipment slot.  Returns false if it was already empty.
+pub async fn delete_user_equipment(user_id: i32, slot: EquipmentSlot) -> sqlx::Result<bool> {
+  let res = sqlx::query!(
+    "DELETE FROM user_equipment WHERE user_id = $1 AND slot = $2",
+    user_id,
+    slot.as_str_name()
+  )
+  .execute(pool())
+  .await?;
+  Ok(res.rows_affected() > 0)
+}
+
+/// Sets whether an item or stack in the user's inventory is locked.  Returns false if the user has
+/// no item or stack with that id.
+pub async fn set_item_locked(user_id: i32, id: Uuid, locked: bool) -> sqlx::Result<bool> {
+  let res = sqlx::query!(
+    "UPDATE inventory SET locked = $3 WHERE id = $1 AND user_id = $2",
+    id,
+    user_id,
+    locked
+  )
+  .execute(pool())
+  .await?;
+  if res.rows_affected() > 0 {
+    return Ok(true);
+  }
+
+  let res = sqlx::query!(
+    "UPDATE inventory_stacks SET locked = $3 WHERE id = $1 AND user_id = $2",
+    id,
+    user_id,
+    locked
+  )
+  .execute(pool())
+  .await?;
+  Ok(res.rows_affected() > 0)
+}
+
+/// Removes every item and stack from the user's inventory, locked ones included, recording them all
+/// in the ledger.
+pub async fn clear_user_inventory(
+  txn: &mut sqlx::Transaction<'_, Postgres>,
+  user_id: i32,
+  reason: &InventoryChangeReason,
+) -> sqlx::Result<()> {
+  lock_inventory_count(txn, user_id).await?;
+
+  sqlx::query!(
+    "INSERT INTO inventory_ledger (user_id, item_id, item_uuid, count_change, quality_change, \
+     value_change, reason, reason_detail) SELECT user_id, item_id, id, -1, -quality, -value, $2, \
+     $3 FROM inventory WHERE user_id = $1 UNION ALL SELECT user_id, item_id, id, -count, \
+     -total_quality, -total_value, $2, $3 FROM inventory_stacks WHERE user_id = $1 AND count > 0",
+    user_id,
+    reason.ledger_reason().as_str_name(),
+    reason.detail(),
+  )
+  .execute(&mut **txn)
+  .await?;
+
+  sqlx::query!("DELETE FROM inventory WHERE user_id = $1", user_id)
+    .execute(&mut **txn)
+    .await?;
+  sqlx::query!("DELETE FROM inventory_stacks WHERE user_id = $1", user_id)
+    .execute(&mut **txn)
+    .await?;
+  sqlx::query!(
+    "DELETE FROM inventory_histograms WHERE user_id = $1",
+    user_id
+  )
+  .execute(&mut **txn)
+  .await?;
+  sqlx::query!(
+    "UPDATE inventory_counts SET item_count = 0 WHERE user_id = $1",
+    user_id
+  )
+  .execute(&mut **txn)
+  .await?;
+  Ok(())
 }
 
-/// Locks a user's inventory for use in a transaction.  Returns all items in the user's inventory.
+/// Locks a user's inventory for use in a transaction.  Returns all items in the user's inventory,
+/// with equipped items marked as locked.
 pub async fn lock_user_inventory(
   txn: &mut sqlx::Transaction<'_, Postgres>,
   user_id: i32,
 ) -> sqlx::Result<Vec<DbItem>> {
   sqlx::query_as!(
     DbItem,
-    "SELECT id, item_id, quality, value, modifiers FROM inventory WHERE user_id = $1 FOR UPDATE",
+    "SELECT id, item_id, quality, value, modifiers, 0 AS \"stack_count!\", locked OR EXISTS \
+     (SELECT 1 FROM user_equipment e WHERE e.item_uuid = inventory.id) AS \"locked!\" FROM \
+     inventory WHERE user_id = $1 FOR UPDATE",
     user_id
   )
   .fetch_all(&mut **txn)
   .await
 }
 
+/// Consumes items from the user's inventory to pay `debits`, choosing them with `strategy`.
+/// Returns the items consumed for each cost.  Locked items and stacks, and equipped items, are
+/// never consumed.
+///
+/// If the user can't cover every cost, this fails with `resource_exhausted` naming everything
+/// that's missing, unless `allow_shortfall` is set.  Then all of an item is consumed when it isn't
+/// enough, and the shortfall is reported in the returned debits.  That's only meant for previews
+/// whose transaction is rolled back.
 pub async fn debit_user_inventory(
   txn: &mut sqlx::Transaction<'_, Postgres>,
   user_id: i32,
   debits: &[ItemCost],
-) -> Result<(), Status> {
-  let inventory = lock_user_inventory(txn, user_id).await.map_err(|err| {
+  strategy: &DebitStrategy,
+  allow_shortfall: bool,
+  reason: &InventoryChangeReason,
+) -> Result<Vec<ItemCostDebit>, Status> {
+  let map_err = |err: sqlx::Error| {
     error!("Failed to lock user inventory: {err}");
     Status::internal("Internal DB error")
-  })?;
-  let mut items_by_id: FxHashMap<i32, Vec<DbItem>> =
-    inventory
-      .into_iter()
-      .fold(FxHashMap::default(), |mut map, item| {
-        map.entry(item.item_id).or_insert_with(Vec::new).push(item);
-        map
-      });
+  };
+  lock_inventory_count(txn, user_id).await.map_err(map_err)?;
+  let inventory = lock_user_inventory(txn, user_id).await.map_err(map_err)?;
+  let mut stacks = lock_item_stacks(txn, &[user_id]).await.map_err(map_err)?;
+  // Prestiging empties the inventory, so everything in it was found at the user's current level
+  let prestige_level =
+    sqlx::query_scalar!("SELECT prestige_level FROM users WHERE id = $1", user_id)
+      .fetch_one(&mut **txn)
+      .await
+      .map_err(map_err)? as u32;
 
-  // We debit items from lowest quality to highest, so we sort the items from highest to lowest
-  // quality
+  // Locked and equipped items can't be spent, so they're left out entirely.  Equipped items come
+  // back from `lock_user_inventory` as locked.
+  if let DebitStrategy::ExplicitItems(uuids) = strategy {
+    let locked = inventory
+      .iter()
+      .filter(|item| item.locked)
+      .map(|item| item.id)
+      .chain(
+        stacks
+          .values()
+          .filter(|stack| stack.locked)
+          .map(|stack| stack.id),
+      )
+      .find(|id| uuids.contains(id));
+    if let Some(locked) = locked {
+      return Err(Status::invalid_argument(format!(
+        "Item {locked} is locked or equipped"
+      )));
+    }
+  }
+  stacks.retain(|_, stack| !stack.locked);
+  let mut items_by_id: FxHashMap<i32, Vec<DbItem>> = inventory
+    .into_iter()
+    .filter(|item| !item.locked)
+    .fold(FxHashMap::default(), |mut map, item| {
+      map.entry(item.item_id).or_insert_with(Vec::new).push(item);
+      map
+    });
+
+  // Items are selected from lowest quality to highest
   for items in items_by_id.values_mut() {
-    items.sort_unstable_by(|a, b| b.quality.partial_cmp(&a.quality).unwrap());
+    items.sort_unstable_by(|a, b| a.quality.partial_cmp(&b.quality).unwrap());
   }
 
+  let buckets = quality_histogram_buckets();
   let mut item_ids_to_delete = Vec::new();
+  let mut slot_delta = 0;
+  let mut histogram_deltas: FxHashMap<(i32, i32), HistogramDelta> = FxHashMap::default();
+  let mut explicit_items_used = FxHashSet::default();
+  let mut item_cost_debits = Vec::with_capacity(debits.len());
   for debit in debits {
-    let item = items_by_id.get_mut(&(debit.item_id as _)).ok_or_else(|| {
-      Status::not_found(format!(
-        "Item {:?} not found in inventory",
-        get_item_display_name_by_id(debit.item_id as u32)
-      ))
-    })?;
+    let item_id = debit.item_id as i32;
+    let stack = stacks.get_mut(&(user_id, item_id));
+    let items = items_by_id.entry(item_id).or_default();
+    let available_quality = items.iter().map(|item| item.quality).sum::<f32>()
+      + stack
+        .as_ref()
+        .map_or(0., |stack| stack.min_total_quality() as f32);
     let mut remaining_quality = debit.total_quality;
+    let mut consumed_items = Vec::new();
+    let histogram_delta = histogram_deltas
+      .entry((user_id, item_id))
+      .or_insert_with(|| HistogramDelta::new(buckets));
 
-    // Pop items from the back of the list - taking the lowest quality items first - until we've
-    // debited the total quality
-    while let Some(item) = item.pop() {
-      item_ids_to_delete.push(item.id);
-      remaining_quality -= item.quality;
-      if remaining_quality <= 0.0 {
-        break;
+    // With explicit items, the listed individual items are all consumed and the stack only covers
+    // what they don't.  Otherwise, stacked items are taken first.
+    let (selected, use_stack) = match strategy {
+      DebitStrategy::ExplicitItems(uuids) => {
+        let selected: Vec<usize> = (0..items.len())
+          .filter(|&ix| uuids.contains(&items[ix].id))
+          .collect();
+        remaining_quality -= selected.iter().map(|&ix| items[ix].quality).sum::<f32>();
+        let use_stack = stack
+          .as_ref()
+          .is_some_and(|stack| uuids.contains(&stack.id));
+        (Some(selected), use_stack)
+      },
+      _ => (None, true),
+    };
+
+    if let Some(stack) = stack.filter(|_| use_stack) {
+      if remaining_quality > 0. {
+        let stack_size = get_item_stack_size(debit.item_id);
+        let slots_before = stack_slots(stack.count, stack_size);
+        let removed = stack.take_lowest(remaining_quality, |quality| {
+          compute_item_value(debit.item_id, quality, &[], prestige_level)
+        });
+        let spent_quality = histogram_min_quality(&removed.quality_histogram);
+        remaining_quality -= spent_quality as f32;
+        histogram_delta.remove_all(&removed);
+        slot_delta += stack_slots(stack.count, stack_size) - slots_before;
+        consumed_items.push(Item {
+          item_type_id: item_id,
+          quality: (spent_quality / removed.count.max(1) as f64) as f32,
+          value: removed.total_value as f32,
+          modifiers: Vec::new(),
+          item_uuid: stack.id.to_string(),
+          stack_count: removed.count as _,
+          locked: false,
+        });
       }
+      explicit_items_used.insert(stack.id);
+    }
+
+    let selected = match selected {
+      Some(selected) => selected,
+      None => {
+        let qualities: Vec<f32> = items.iter().map(|item| item.quality).collect();
+        let selected = match strategy {
+          DebitStrategy::MinimalOvershoot =>
+            select_minimal_overshoot(&qualities, remaining_quality),
+          _ => select_lowest_quality_first(&qualities, remaining_quality),
+        };
+        // If the items can't cover the cost, count all of them to report how much is missing
+        let selected = selected.unwrap_or_else(|| (0..items.len()).collect());
+        remaining_quality -= selected.iter().map(|&ix| items[ix].quality).sum::<f32>();
+        selected
+      },
+    };
+
+    // Remove the selected items back to front so the indices stay valid
+    for ix in selected.into_iter().rev() {
+      let item = items.remove(ix);
+      item_ids_to_delete.push(item.id);
+      explicit_items_used.insert(item.id);
+      histogram_delta.remove(item.quality, item.value);
+      consumed_items.push(db_item_to_proto(item).map_err(|err| {
+        error!("Found item with un-parseable modifiers in DB: {err}");
+        Status::internal("Internal DB error")
+      })?);
     }
 
-    if remaining_quality > 0.0 {
+    item_cost_debits.push(ItemCostDebit {
+      cost: Some(debit.clone()),
+      consumed_quality: consumed_items
+        .iter()
+        .map(|item| match item.stack_count {
+          0 => item.quality,
+          count => item.quality * count as f32,
+        })
+        .fold(0., |total, quality| total + quality),
+      consumed_items,
+      available_quality,
+      missing_quality: remaining_quality.max(0.),
+    });
+  }
+
+  if !allow_shortfall {
+    let missing: Vec<String> = item_cost_debits
+      .iter()
+      .filter(|debit| debit.missing_quality > 0.)
+      .map(|debit| {
+        format!(
+          "{} total quality of {:?}",
+          debit.missing_quality,
+          get_item_display_name_by_id(debit.cost.as_ref().unwrap().item_id)
+        )
+      })
+      .collect();
+    if !missing.is_empty() {
       return Err(Status::resource_exhausted(format!(
-        "Not enough quality in inventory for item {:?}; missing {} total quality",
-        get_item_display_name_by_id(debit.item_id as u32),
-        remaining_quality
+        "Not enough quality in inventory; missing {}",
+        missing.join(", ")
+      )));
+    }
+  }
+
+  if let DebitStrategy::ExplicitItems(uuids) = strategy {
+    if let Some(unused) = uuids.difference(&explicit_items_used).next() {
+      return Err(Status::invalid_argument(format!(
+        "Item {unused} isn't in the inventory or can't be used to pay this cost"
       )));
     }
   }
@@ -493,7 +2346,56 @@ pub async fn debit_user_inventory(
     Status::internal("Internal DB error")
   })?;
 
-  Ok(())
+  let debited_item_ids: FxHashSet<i32> = debits.iter().map(|debit| debit.item_id as i32).collect();
+  for stack in stacks
+    .values()
+    .filter(|stack| debited_item_ids.contains(&stack.item_id))
+  {
+    save_item_stack(txn, user_id, stack).await.map_err(|err| {
+      error!("Failed to save debited item stack: {err}");
+      Status::internal("Internal DB error")
+    })?;
+  }
+
+  update_inventory_histograms(txn, &histogram_deltas)
+    .await
+    .map_err(|err| {
+      error!("Failed to update inventory histograms: {err}");
+      Status::internal("Internal DB error")
+    })?;
+
+  let ledger_changes: Vec<LedgerChange> = item_cost_debits
+    .iter()
+    .flat_map(|debit| &debit.consumed_items)
+    .map(|item| {
+      let count = (item.stack_count as i32).max(1);
+      LedgerChange {
+        user_id,
+        item_id: item.item_type_id,
+        item_uuid: Uuid::parse_str(&item.item_uuid).unwrap(),
+        count: -count,
+        quality: -(item.quality as f64 * count as f64),
+        value: -item.value as f64,
+        reason: reason.clone(),
+      }
+    })
+    .collect();
+  insert_ledger_entries(txn, &ledger_changes)
+    .await
+    .map_err(|err| {
+      error!("Failed to record debit in inventory ledger: {err}");
+      Status::internal("Internal DB error")
+    })?;
+
+  slot_delta -= item_ids_to_delete.len() as i32;
+  update_inventory_count(txn, user_id, slot_delta)
+    .await
+    .map_err(|err| {
+      error!("Failed to update inventory count: {err}");
+      Status::internal("Internal DB error")
+    })?;
+
+  Ok(item_cost_debits)
 }
 
 pub(crate) async fn get_user_upgrades(user_id: i32) -> sqlx::Result<Upgrades> {
@@ -504,8 +2406,7 @@ pub(crate) async fn get_user_upgrades(user_id: i32) -> sqlx::Result<Upgrades> {
   .fetch_optional(pool())
   .await?;
 
-  let total_inventory_capacity = BASE_INVENTORY_SIZE as i32
-    + storage_upgrade_level.unwrap_or(0) * INVENTORY_CAPACITY_PER_UPGRADE as i32;
+  let total_inventory_capacity = get_inventory_capacity(storage_upgrade_level.unwrap_or(0));
   let storage_level = storage_upgrade_level.unwrap_or(0) as _;
 
   Ok(Upgrades {
@@ -516,3 +2417,82 @@ pub(crate) async fn get_user_upgrades(user_id: i32) -> sqlx::Result<Upgrades> {
     }),
   })
 }
+
+#[tokio::test]
+#[ignore = "needs a migrated database at `DATABASE_URL`"]
+async fn test_debit_skips_equipped_items() {
+  use crate::game::{
+    items::{get_item_id_by_name, init_loot_tables},
+    stacks::init_quality_histograms,
+  };
+
+  let mut settings = Settings::default();
+  settings.database.url = std::env::var("DATABASE_URL").ok();
+  init_quality_histograms(&settings).unwrap();
+  init_db(&settings).await.unwrap();
+  init_loot_tables().unwrap();
+
+  let user_id = insert_guest_user(&format!("test-{}", Uuid::new_v4().simple()))
+    .await
+    .unwrap();
+  let item_id = get_item_id_by_name("neodymium_magnet");
+  let new_item = |quality| NewInventoryItem {
+    id: Uuid::new_v4(),
+    user_id,
+    item_id: item_id as i32,
+    quality,
+    value: 1.,
+    modifiers: None,
+    reason: InventoryChangeReason::Mined {
+      location_name: "test",
+    },
+  };
+  let (equipped, spare) = (new_item(0.2), new_item(0.8));
+  let (equipped_id, spare_id) = (equipped.id, spare.id);
+  insert_inventory_items(&[equipped, spare]).await.unwrap();
+  assert!(
+    set_user_equipment(user_id, EquipmentSlot::Accessory, equipped_id)
+      .await
+      .unwrap()
+  );
+
+  let costs = [ItemCost {
+    item_id,
+    total_quality: 0.1,
+  }];
+  let reason = InventoryChangeReason::StorageUpgrade { level: 1 };
+  let mut txn = pool().begin().await.unwrap();
+  let debited = debit_user_inventory(
+    &mut txn,
+    user_id,
+    &costs,
+    &DebitStrategy::LowestQualityFirst,
+    false,
+    &reason,
+  )
+  .await
+  .unwrap();
+  txn.commit().await.unwrap();
+  // The equipped item is lower quality, but the spare one is used instead
+  assert_eq!(debited[0].consumed_items.len(), 1);
+  assert_eq!(debited[0].consumed_items[0].item_uuid, spare_id.to_string());
+  let equipment = get_user_equipment(user_id).await.unwrap();
+  assert_eq!(equipment.len(), 1);
+  assert_eq!(
+    equipment[0].item.as_ref().unwrap().item_uuid,
+    equipped_id.to_string()
+  );
+
+  // Equipped items can't be picked explicitly either
+  let mut txn = pool().begin().await.unwrap();
+  let res = debit_user_inventory(
+    &mut txn,
+    user_id,
+    &costs,
+    &DebitStrategy::ExplicitItems([equipped_id].into_iter().collect()),
+    false,
+    &reason,
+  )
+  .await;
+  assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+}
@@ -0,0 +1,251 @@
+use foundations::BootstrapResult;
+use fxhash::FxHashSet;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::{
+  db::{
+    get_user_achievements, get_user_items_mined, get_user_storage_upgrade_level,
+    insert_user_achievements,
+  },
+  protos::{Achievement, Item},
+};
+
+use super::items::{get_item_rarity_tier, mine_locations};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum AchievementCondition {
+  ItemFound {
+    #[serde(default)]
+    min_rarity_tier: u32,
+    #[serde(default)]
+    min_quality: f32,
+  },
+  /// Items mined at `location`, or at all locations combined if it's not set
+  ItemsMined {
+    location: Option<String>,
+    count: i64,
+  },
+  StorageLevel {
+    level: i32,
+  },
+}
+
+#[derive(Deserialize)]
+pub struct AchievementDefinition {
+  pub id: u32,
+  pub name: String,
+  pub display_name: String,
+  pub description: String,
+  pub condition: AchievementCondition,
+}
+
+impl AchievementDefinition {
+  pub fn to_proto(&self, unlocked_at: Option<i64>) -> Achievement {
+    Achievement {
+      id: self.id,
+      name: self.name.clone(),
+      display_name: self.display_name.clone(),
+      description: self.description.clone(),
+      unlocked_at,
+    }
+  }
+}
+
+static ACHIEVEMENTS: OnceCell<Vec<AchievementDefinition>> = OnceCell::new();
+
+pub fn achievements() -> &'static [AchievementDefinition] {
+  ACHIEVEMENTS.get().expect("Achievements not initialized")
+}
+
+/// Must be called after `init_loot_tables`.
+pub fn init_achievements() -> BootstrapResult<()> {
+  let achievements: Vec<AchievementDefinition> =
+    serde_yaml::from_str(include_str!("achievements.yml"))?;
+
+  let mut ids = FxHashSet::default();
+  for achievement in &achievements {
+    if !ids.insert(achievement.id) {
+      return Err(anyhow::anyhow!(
+        "Duplicate achievement ID {}",
+        achievement.id
+      ));
+    }
+    if let AchievementCondition::ItemsMined {
+      location: Some(location),
+      ..
+    } = &achievement.condition
+    {
+      if !mine_locations()
+        .iter()
+        .any(|loc| &loc.descriptor.name == location)
+      {
+        return Err(anyhow::anyhow!(
+          "Achievement {} refers to unknown mine location {location}",
+          achievement.name
+        ));
+      }
+    }
+  }
+
+  let count = achievements.len();
+  ACHIEVEMENTS
+    .set(achievements)
+    .map_err(|_| anyhow::anyhow!("Achievements already initialized"))?;
+  info!("Initialized {count} achievements");
+
+  Ok(())
+}
+
+/// Unlocks achievements for the user and returns the ones that weren't already unlocked.  Errors
+/// are logged rather than returned so that they don't interrupt whatever unlocked the achievements.
+async fn unlock(user_id: i32, achievement_ids: Vec<i32>) -> Vec<Achievement> {
+  if achievement_ids.is_empty() {
+    return Vec::new();
+  }
+
+  match insert_user_achievements(user_id, &achievement_ids).await {
+    Ok(unlocked) => unlocked
+      .into_iter()
+      .filter_map(|(id, unlocked_at)| {
+        let achievement = achievements()
+          .iter()
+          .find(|achievement| achievement.id == id)?;
+        info!("User {user_id} unlocked achievement {}", achievement.name);
+        Some(achievement.to_proto(Some(unlocked_at)))
+      })
+      .collect(),
+    Err(err) => {
+      error!("Failed to unlock achievements for user {user_id}: {err}");
+      Vec::new()
+    },
+  }
+}
+
+/// Unlocks the storage level achievements for a user whose storage was just upgraded to
+/// `storage_level`.
+pub async fn check_storage_achievements(user_id: i32, storage_level: i32) -> Vec<Achievement> {
+  let achievement_ids = achievements()
+    .iter()
+    .filter(|achievement| {
+      matches!(
+        achievement.condition,
+        AchievementCondition::StorageLevel { level } if level <= storage_level
+      )
+    })
+    .map(|achievement| achievement.id as i32)
+    .collect();
+  unlock(user_id, achievement_ids).await
+}
+
+/// Tracks a mining session's progress towards achievements, so that they can be checked on every
+/// tick without reading from the DB.
+pub struct MiningAchievementTracker {
+  user_id: i32,
+  location_name: &'static str,
+  locked: Vec<&'static AchievementDefinition>,
+  items_mined_here: i64,
+  items_mined_total: i64,
+}
+
+impl MiningAchievementTracker {
+  /// Also unlocks any achievements whose conditions were already met before the session started,
+  /// such as ones that were added after the user reached them, and returns them.
+  pub async fn start(
+    user_id: i32,
+    location_name: &'static str,
+  ) -> sqlx::Result<(Self, Vec<Achievement>)> {
+    let unlocked = get_user_achievements(user_id).await?;
+    let items_mined = get_user_items_mined(user_id).await?;
+    let storage_level = get_user_storage_upgrade_level(user_id).await?;
+
+    let mut tracker = MiningAchievementTracker {
+      user_id,
+      location_name,
+      locked: achievements()
+        .iter()
+        .filter(|achievement| !unlocked.contains_key(&achievement.id))
+        .collect(),
+      items_mined_here: items_mined.get(location_name).copied().unwrap_or(0),
+      items_mined_total: items_mined.values().sum(),
+    };
+    let unlocked = tracker
+      .unlock_where(|tracker, condition| match condition {
+        AchievementCondition::ItemFound { .. } => false,
+        AchievementCondition::ItemsMined {
+          location: Some(location),
+          count,
+        } => items_mined.get(location).copied().unwrap_or(0) >= *count,
+        AchievementCondition::ItemsMined {
+          location: None,
+          count,
+        } => tracker.items_mined_total >= *count,
+        AchievementCondition::StorageLevel { level } => *level <= storage_level,
+      })
+      .await;
+    Ok((tracker, unlocked))
+  }
+
+  /// Call once `item` has been mined and stored.  Returns any achievements it unlocked.
+  pub async fn item_stored(&mut self, item: &Item) -> Vec<Achievement> {
+    self.items_mined_here += 1;
+    self.items_mined_total += 1;
+    let rarity_tier = get_item_rarity_tier(item.item_type_id as _);
+
+    self
+      .unlock_where(|tracker, condition| match condition {
+        AchievementCondition::ItemFound {
+          min_rarity_tier,
+          min_quality,
+        } => rarity_tier >= *min_rarity_tier && item.quality >= *min_quality,
+        AchievementCondition::ItemsMined {
+          location: Some(location),
+          count,
+        } => location == tracker.location_name && tracker.items_mined_here >= *count,
+        AchievementCondition::ItemsMined {
+          location: None,
+          count,
+        } => tracker.items_mined_total >= *count,
+        AchievementCondition::StorageLevel { .. } => false,
+      })
+      .await
+  }
+
+  async fn unlock_where(
+    &mut self,
+    met: impl Fn(&Self, &AchievementCondition) -> bool,
+  ) -> Vec<Achievement> {
+    let met_ids: Vec<i32> = self
+      .locked
+      .iter()
+      .filter(|achievement| met(self, &achievement.condition))
+      .map(|achievement| achievement.id as i32)
+      .collect();
+    self
+      .locked
+      .retain(|achievement| !met_ids.contains(&(achievement.id as i32)));
+    unlock(self.user_id, met_ids).await
+  }
+}
+
+#[test]
+fn test_achievements_parse() {
+  let achievements: Vec<AchievementDefinition> =
+    serde_yaml::from_str(include_str!("achievements.yml")).unwrap();
+  assert!(matches!(
+    achievements[0].condition,
+    AchievementCondition::ItemsMined {
+      location: None,
+      count: 1
+    }
+  ));
+  assert!(achievements.iter().any(|achievement| matches!(
+    achievement.condition,
+    AchievementCondition::ItemFound {
+      min_rarity_tier: 5,
+      ..
+    }
+  )));
+}
@@ -0,0 +1,106 @@
+// Strategy parsing returns `Status` so it can be passed straight back to gRPC callers
+#![allow(clippy::result_large_err)]
+
+use fxhash::FxHashSet;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::protos::{self, DebitSelection};
+
+/// How the items consumed to pay an item cost are chosen.
+pub enum DebitStrategy {
+  LowestQualityFirst,
+  MinimalOvershoot,
+  ExplicitItems(FxHashSet<Uuid>),
+}
+
+impl DebitStrategy {
+  pub fn from_selection(selection: Option<DebitSelection>) -> Result<Self, Status> {
+    let Some(selection) = selection else {
+      return Ok(DebitStrategy::LowestQualityFirst);
+    };
+
+    match protos::DebitStrategy::try_from(selection.strategy) {
+      Ok(protos::DebitStrategy::LowestQualityFirst) => Ok(DebitStrategy::LowestQualityFirst),
+      Ok(protos::DebitStrategy::MinimalOvershoot) => Ok(DebitStrategy::MinimalOvershoot),
+      Ok(protos::DebitStrategy::ExplicitItems) => selection
+        .item_uuids
+        .iter()
+        .map(|uuid| {
+          Uuid::parse_str(uuid)
+            .map_err(|_| Status::invalid_argument(format!("Invalid item UUID: {uuid}")))
+        })
+        .collect::<Result<_, _>>()
+        .map(DebitStrategy::ExplicitItems),
+      Err(_) => Err(Status::invalid_argument("Invalid debit strategy")),
+    }
+  }
+}
+
+/// Chooses items to cover `quality`, taking the lowest-quality items first.  `qualities` must be
+/// sorted in ascending order.  Returns the indices of the chosen items, or `None` if all of them
+/// together don't cover `quality`.
+pub fn select_lowest_quality_first(qualities: &[f32], quality: f32) -> Option<Vec<usize>> {
+  let mut remaining_quality = quality;
+  let mut selected = Vec::new();
+  for (ix, &item_quality) in qualities.iter().enumerate() {
+    if remaining_quality <= 0. {
+      break;
+    }
+    selected.push(ix);
+    remaining_quality -= item_quality;
+  }
+
+  if remaining_quality > 0. {
+    return None;
+  }
+  Some(selected)
+}
+
+/// Chooses items to cover `quality` while consuming as little extra quality as possible.
+/// `qualities` must be sorted in ascending order.  Returns the indices of the chosen items, or
+/// `None` if all of them together don't cover `quality`.
+///
+/// This is a heuristic: it starts from the lowest-quality-first selection, then repeatedly drops
+/// the highest-quality item that can be removed without dropping below `quality`.
+pub fn select_minimal_overshoot(qualities: &[f32], quality: f32) -> Option<Vec<usize>> {
+  let mut selected = select_lowest_quality_first(qualities, quality)?;
+  let mut overshoot = selected.iter().map(|&ix| qualities[ix]).sum::<f32>() - quality;
+
+  loop {
+    let droppable = selected
+      .iter()
+      .enumerate()
+      .filter(|(_, &ix)| qualities[ix] <= overshoot)
+      .max_by(|(_, &a), (_, &b)| qualities[a].partial_cmp(&qualities[b]).unwrap());
+    let Some((selected_ix, &ix)) = droppable else {
+      break;
+    };
+
+    overshoot -= qualities[ix];
+    selected.remove(selected_ix);
+  }
+
+  Some(selected)
+}
+
+#[test]
+fn test_debit_selection() {
+  let qualities = [0.02, 0.3, 0.3, 0.99];
+
+  assert_eq!(
+    select_lowest_quality_first(&qualities, 0.5),
+    Some(vec![0, 1, 2])
+  );
+  assert_eq!(select_lowest_quality_first(&qualities, 2.), None);
+
+  // 0.02 + 0.3 + 0.3 covers 0.61 with almost no overshoot
+  assert_eq!(
+    select_minimal_overshoot(&qualities, 0.61),
+    Some(vec![0, 1, 2])
+  );
+  // Lowest first would take all four items; the 0.99 alone overshoots the least
+  let selected = select_minimal_overshoot(&qualities, 0.63).unwrap();
+  assert_eq!(selected, vec![3]);
+  assert_eq!(select_minimal_overshoot(&qualities, 2.), None);
+}
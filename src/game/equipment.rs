@@ -0,0 +1,103 @@
+use serde::{de::Error, Deserialize, Deserializer};
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::{
+  db::{
+    delete_user_equipment, get_user_equipment, get_user_item_type, get_user_prestige_level,
+    set_user_equipment,
+  },
+  protos::{Equipment, EquipmentSlot, EquippedItem, LootBonuses},
+};
+
+use super::items::{get_item_equipment_stats, RollModifiers};
+
+/// Reads an `EquipmentSlot` by name, e.g. `Tool`, from item tables.
+pub fn deserialize_slot<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let name = String::deserialize(deserializer)?;
+  EquipmentSlot::from_str_name(&name)
+    .map(|slot| slot as i32)
+    .ok_or_else(|| D::Error::custom(format!("Unknown equipment slot {name}")))
+}
+
+/// Adds up the bonuses of the equipped items, each scaled by the item's quality.
+fn total_bonuses(items: &[EquippedItem]) -> LootBonuses {
+  let mut total = LootBonuses::default();
+  for item in items.iter().filter_map(|equipped| equipped.item.as_ref()) {
+    let Some(bonuses) =
+      get_item_equipment_stats(item.item_type_id as _).and_then(|stats| stats.bonuses.as_ref())
+    else {
+      continue;
+    };
+    total.rarity_bonus += bonuses.rarity_bonus * item.quality;
+    total.quality_bonus += bonuses.quality_bonus * item.quality;
+    total.extra_rolls += bonuses.extra_rolls * item.quality;
+  }
+  total
+}
+
+/// Loads everything that changes the user's loot rolls.
+pub async fn load_roll_modifiers(user_id: i32) -> Result<RollModifiers, Status> {
+  let prestige_level = get_user_prestige_level(user_id).await.map_err(|err| {
+    error!("Failed to get prestige level: {err}");
+    Status::internal("Internal DB error")
+  })?;
+  let equipped = get_user_equipment(user_id).await?;
+
+  Ok(RollModifiers {
+    prestige_level,
+    bonuses: total_bonuses(&equipped),
+  })
+}
+
+pub(crate) async fn get_equipment(user_id: i32) -> Result<Equipment, Status> {
+  let items = get_user_equipment(user_id).await?;
+  Ok(Equipment {
+    bonuses: Some(total_bonuses(&items)),
+    items,
+  })
+}
+
+pub(crate) async fn equip(user_id: i32, item_uuid: &str) -> Result<Equipment, Status> {
+  let item_uuid =
+    Uuid::parse_str(item_uuid).map_err(|_| Status::invalid_argument("Invalid item UUID"))?;
+
+  let item_id = get_user_item_type(user_id, item_uuid)
+    .await
+    .map_err(|err| {
+      error!("Failed to fetch item to equip: {err}");
+      Status::internal("Internal DB error")
+    })?
+    .ok_or_else(|| Status::not_found("Item not found"))?;
+  let slot = get_item_equipment_stats(item_id as _)
+    .and_then(|stats| EquipmentSlot::try_from(stats.slot).ok())
+    .ok_or_else(|| Status::invalid_argument("Item can't be equipped"))?;
+
+  let equipped = set_user_equipment(user_id, slot, item_uuid)
+    .await
+    .map_err(|err| {
+      error!("Failed to equip item: {err}");
+      Status::internal("Internal DB error")
+    })?;
+  // The item was removed from the inventory since it was looked up
+  if !equipped {
+    return Err(Status::not_found("Item not found"));
+  }
+
+  get_equipment(user_id).await
+}
+
+pub(crate) async fn unequip(user_id: i32, slot: i32) -> Result<Equipment, Status> {
+  let slot = EquipmentSlot::try_from(slot)
+    .map_err(|_| Status::invalid_argument("Invalid equipment slot"))?;
+
+  delete_user_equipment(user_id, slot).await.map_err(|err| {
+    error!("Failed to unequip item: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  get_equipment(user_id).await
+}
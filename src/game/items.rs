@@ -3,17 +3,19 @@ use std::f32::consts::E;
 use foundations::BootstrapResult;
 use fxhash::FxHashMap;
 use once_cell::sync::OnceCell;
-use rand::{seq::SliceRandom, Rng};
+use rand::{distributions::WeightedIndex, Rng};
 use rand_distr::Distribution;
 use scrypt::password_hash::rand_core::RngCore;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
 use crate::{
   db::insert_item_descriptors,
-  protos::{Item, ItemDescriptor, ItemModifier, LocationDescriptor},
+  protos::{EquipmentStats, Item, ItemDescriptor, ItemModifier, LocationDescriptor, LootBonuses},
 };
 
+use super::prestige::get_prestige_value_multiplier;
+
 static ITEM_DESCRIPTORS: OnceCell<Vec<ItemDescriptor>> = OnceCell::new();
 static ITEM_DESCRIPTOR_BY_ID: OnceCell<FxHashMap<u32, ItemDescriptor>> = OnceCell::new();
 static ITEM_ID_BY_NAME: OnceCell<FxHashMap<String, u32>> = OnceCell::new();
@@ -37,6 +39,23 @@ pub fn get_item_display_name_by_id(id: u32) -> &'static str {
   &get_item_descriptor_by_id(id).display_name
 }
 
+/// Max number of the item in a single stack, or `None` if it isn't stackable.
+pub fn get_item_stack_size(id: u32) -> Option<i32> {
+  ITEM_DESCRIPTOR_BY_ID
+    .get()
+    .expect("Item descriptor by ID not initialized")
+    .get(&id)
+    .and_then(|item| item.stack_size)
+    .map(|stack_size| stack_size as i32)
+}
+
+pub fn get_item_rarity_tier(id: u32) -> u32 { get_item_descriptor_by_id(id).rarity_tier }
+
+/// The item's slot and bonuses, or `None` if it can't be equipped.
+pub fn get_item_equipment_stats(id: u32) -> Option<&'static EquipmentStats> {
+  get_item_descriptor_by_id(id).equipment.as_ref()
+}
+
 fn get_item_descriptor_by_id(id: u32) -> &'static ItemDescriptor {
   ITEM_DESCRIPTOR_BY_ID
     .get()
@@ -51,9 +70,9 @@ pub async fn populate_items_table() -> BootstrapResult<()> {
   let mut all_item_descriptors = Vec::new();
   for table in item_tables {
     let items: Vec<ItemDescriptor> = serde_yaml::from_str::<Vec<ItemDescriptor>>(table)?;
-    insert_item_descriptors(&items).await?;
     all_item_descriptors.extend(items);
   }
+  insert_item_descriptors(&all_item_descriptors).await?;
 
   let item_id_by_name = all_item_descriptors
     .iter()
@@ -93,14 +112,19 @@ pub enum QualityDistribution {
 }
 
 impl QualityDistribution {
-  /// Generates a quality value from 0.0 to 1.0 based on the distribution.
-  pub fn gen(&self, rng: &mut impl RngCore) -> f32 {
+  /// Generates a quality value from 0.0 to 1.0 based on the distribution.  A positive
+  /// `quality_bonus` skews it towards higher values.
+  pub fn gen(&self, rng: &mut impl RngCore, quality_bonus: f32) -> f32 {
     match self {
-      QualityDistribution::Uniform => rng.gen_range(0.0..1.0),
+      QualityDistribution::Uniform => rng
+        .gen_range(0.0..1.0f32)
+        .powf(1. / (1. + quality_bonus.max(0.))),
       QualityDistribution::Normal { mean, std_dev } => {
+        // The mean is capped so that enough samples still land inside the range
+        let mean = (mean + quality_bonus.max(0.)).min(mean.max(0.95));
         let mut sample = 0.0f32;
         while sample <= 0.0 || sample >= 1.0 {
-          let normal = rand_distr::Normal::new(*mean, *std_dev).unwrap();
+          let normal = rand_distr::Normal::new(mean, *std_dev).unwrap();
           sample = normal.sample(rng);
         }
         sample
@@ -162,7 +186,13 @@ fn compute_item_quality_multiplier(quality: f32) -> f32 {
   val * 1.2
 }
 
-fn compute_item_value(id: u32, quality: f32, _modifiers: &[ItemModifier]) -> f32 {
+/// Items found by players who have prestiged are worth more.
+pub fn compute_item_value(
+  id: u32,
+  quality: f32,
+  _modifiers: &[ItemModifier],
+  prestige_level: u32,
+) -> f32 {
   let item_descriptor = get_item_descriptor_by_id(id);
   let base_value = match item_descriptor.rarity_tier {
     0 => 0.2,
@@ -178,14 +208,16 @@ fn compute_item_value(id: u32, quality: f32, _modifiers: &[ItemModifier]) -> f32
 
   // TODO: Implement modifier value calculation
 
-  base_value * quality_multiplier
+  base_value * quality_multiplier * get_prestige_value_multiplier(prestige_level)
 }
 
 impl LootTableItemEntry {
-  pub fn gen(&self, rng: &mut impl RngCore) -> Item {
-    let quality = self.quality_distribution.gen(rng);
+  pub fn gen(&self, rng: &mut impl RngCore, roll_modifiers: &RollModifiers) -> Item {
+    let quality = self
+      .quality_distribution
+      .gen(rng, roll_modifiers.bonuses.quality_bonus);
     let modifiers = self.gen_modifiers(rng);
-    let value = compute_item_value(self.id, quality, &modifiers);
+    let value = compute_item_value(self.id, quality, &modifiers, roll_modifiers.prestige_level);
 
     Item {
       item_type_id: self.id as i32,
@@ -193,6 +225,8 @@ impl LootTableItemEntry {
       quality,
       value,
       item_uuid: Uuid::new_v4().to_string(),
+      stack_count: 0,
+      locked: false,
     }
   }
 
@@ -217,18 +251,68 @@ impl LootTableEntry {
       LootTableEntry::Subtable { weight, .. } => *weight,
     }
   }
+
+  /// Lowest rarity tier of the items this entry can roll
+  fn rarity_tier(&self) -> u32 {
+    match self {
+      LootTableEntry::Item(entry) => get_item_rarity_tier(entry.id),
+      LootTableEntry::Subtable { table, .. } =>
+        table.rarity_tiers.iter().copied().min().unwrap_or(0),
+    }
+  }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(transparent)]
-pub struct LootTable(Vec<LootTableEntry>);
+/// Per-user adjustments to loot rolls, from their prestige level and equipment
+#[derive(Clone, Default)]
+pub struct RollModifiers {
+  pub prestige_level: u32,
+  pub bonuses: LootBonuses,
+}
+
+impl RollModifiers {
+  /// Number of items to roll on a single tick of mining
+  pub fn gen_roll_count(&self, rng: &mut impl RngCore) -> u32 {
+    let extra_rolls = self.bonuses.extra_rolls.max(0.);
+    1 + extra_rolls.trunc() as u32 + rng.gen_bool(extra_rolls.fract() as f64) as u32
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(from = "Vec<LootTableEntry>")]
+pub struct LootTable {
+  entries: Vec<LootTableEntry>,
+  /// Lowest rarity tier of each entry, worked out once when the table is loaded
+  rarity_tiers: Vec<u32>,
+}
+
+impl From<Vec<LootTableEntry>> for LootTable {
+  fn from(entries: Vec<LootTableEntry>) -> Self {
+    let rarity_tiers = entries.iter().map(LootTableEntry::rarity_tier).collect();
+    LootTable {
+      entries,
+      rarity_tiers,
+    }
+  }
+}
+
+impl Serialize for LootTable {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    self.entries.serialize(serializer)
+  }
+}
 
 impl LootTable {
-  pub fn roll(&self, rng: &mut impl RngCore) -> Item {
-    let choice = self.0.choose_weighted(rng, |entry| entry.weight()).unwrap();
+  pub fn roll(&self, rng: &mut impl RngCore, modifiers: &RollModifiers) -> Item {
+    let rarity_bonus = modifiers.bonuses.rarity_bonus.max(0.);
+    let weights = self
+      .entries
+      .iter()
+      .zip(&self.rarity_tiers)
+      .map(|(entry, &rarity_tier)| entry.weight() * (1. + rarity_bonus).powi(rarity_tier as i32));
+    let choice = &self.entries[WeightedIndex::new(weights).unwrap().sample(rng)];
     match choice {
-      LootTableEntry::Item(entry) => entry.gen(rng),
-      LootTableEntry::Subtable { table, .. } => table.roll(rng),
+      LootTableEntry::Item(entry) => entry.gen(rng, modifiers),
+      LootTableEntry::Subtable { table, .. } => table.roll(rng, modifiers),
     }
   }
 }
@@ -251,6 +335,11 @@ pub fn gamble_locations() -> &'static Vec<Location> {
     .expect("Gamble locations not initialized")
 }
 
+/// Whether a location is unlocked for mining or gambling.
+pub fn is_location_unlocked(location: &Location) -> bool {
+  location.descriptor.id == 0 // TODO
+}
+
 pub fn init_loot_tables() -> BootstrapResult<()> {
   let mine_location_descriptors: Vec<LocationDescriptor> =
     serde_yaml::from_str(include_str!("mine_locations.yml"))?;
@@ -306,26 +395,50 @@ pub fn init_loot_tables() -> BootstrapResult<()> {
 
 #[test]
 fn loot_table_serialize() {
-  let table = LootTable(vec![
-    LootTableEntry::Item(LootTableItemEntry {
-      id: 1,
-      weight: 1.0,
-      quality_distribution: QualityDistribution::Uniform,
-    }),
-    LootTableEntry::Subtable {
-      table: Box::new(LootTable(vec![LootTableEntry::Item(LootTableItemEntry {
-        id: 2,
+  let table = LootTable {
+    entries: vec![
+      LootTableEntry::Item(LootTableItemEntry {
+        id: 1,
+        weight: 1.0,
+        quality_distribution: QualityDistribution::Uniform,
+      }),
+      LootTableEntry::Subtable {
+        table: Box::new(LootTable {
+          entries: vec![LootTableEntry::Item(LootTableItemEntry {
+            id: 2,
+            weight: 1.0,
+            quality_distribution: QualityDistribution::Normal {
+              mean: 0.2,
+              std_dev: 0.1,
+            },
+          })],
+          rarity_tiers: vec![0],
+        }),
         weight: 1.0,
-        quality_distribution: QualityDistribution::Normal {
-          mean: 0.2,
-          std_dev: 0.1,
-        },
-      })])),
-      weight: 1.0,
-    },
-  ]);
+      },
+    ],
+    rarity_tiers: vec![0, 0],
+  };
 
   // write to /tmp/loot_table.yml
   let table_str = serde_yaml::to_string(&table).unwrap();
   std::fs::write("/tmp/loot_table.yml", table_str).unwrap();
 }
+
+#[test]
+fn test_quality_bonus() {
+  use rand::SeedableRng;
+
+  let mut rng = pcg_rand::Pcg64::seed_from_u64(0);
+  for distribution in [QualityDistribution::Uniform, QualityDistribution::Normal {
+    mean: 0.3,
+    std_dev: 0.2,
+  }] {
+    let mean = |rng: &mut pcg_rand::Pcg64, bonus: f32| {
+      (0..1000).map(|_| distribution.gen(rng, bonus)).sum::<f32>() / 1000.
+    };
+    let base = mean(&mut rng, 0.);
+    let boosted = mean(&mut rng, 1.);
+    assert!(boosted > base + 0.1, "{base} -> {boosted}");
+  }
+}
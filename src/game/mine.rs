@@ -6,21 +6,23 @@ use std::{
 use dashmap::DashMap;
 use foundations::BootstrapResult;
 use futures::Stream;
-use fxhash::FxHashSet;
 use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
 use rand::{rngs::OsRng, SeedableRng};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::Status;
 use uuid::Uuid;
 
 use crate::{
-  db::{get_available_inventory_space, NewInventoryItem},
+  db::{get_available_inventory_space, InventoryChangeReason, NewInventoryItem},
   protos::StartMiningResponse,
 };
 
-use super::items::mine_locations;
+use super::{
+  achievements::MiningAchievementTracker, equipment::load_roll_modifiers, items::mine_locations,
+  prestige::get_prestige_loot_speed_multiplier,
+};
 
 #[derive(Clone)]
 struct MiningSession {
@@ -33,34 +35,27 @@ lazy_static! {
   static ref ACTIVE_MINING_SESSIONS: DashMap<i32, MiningSession> = DashMap::new();
 }
 
-static INVENTORY_ITEM_SAVE_TX: OnceCell<mpsc::Sender<NewInventoryItem>> = OnceCell::new();
+/// Batches of items waiting to be saved, each with a channel that's told the UUID each item was
+/// stored under, or `None` if it didn't fit.
+type InventoryItemSave = (Vec<NewInventoryItem>, oneshot::Sender<Vec<Option<Uuid>>>);
+
+static INVENTORY_ITEM_SAVE_TX: OnceCell<mpsc::Sender<InventoryItemSave>> = OnceCell::new();
+
+/// Ticks and time spent mining are added to the user's stats once every this many ticks, and when
+/// the session ends.
+const MINING_TIME_RECORD_INTERVAL_TICKS: i64 = 10;
 
 pub enum StopMiningReason {
   Manual,
-  InventoryFull,
+  Prestige,
 }
 
-fn inventory_item_save_tx() -> &'static mpsc::Sender<NewInventoryItem> {
+fn inventory_item_save_tx() -> &'static mpsc::Sender<InventoryItemSave> {
   INVENTORY_ITEM_SAVE_TX
     .get()
     .expect("Inventory item saver not initialized")
 }
 
-fn check_inventory_space(user_ids: Vec<i32>) -> impl std::future::Future<Output = ()> {
-  async move {
-    let mut unique_user_ids = FxHashSet::default();
-    unique_user_ids.extend(user_ids);
-
-    for user_id in unique_user_ids {
-      let available_inventory_space = get_available_inventory_space(user_id).await.unwrap_or(0);
-      if available_inventory_space <= 0 {
-        warn!("User {user_id} inventory full; stopping mining session");
-        stop_mining(user_id, StopMiningReason::InventoryFull, None);
-      }
-    }
-  }
-}
-
 pub async fn start_inventory_item_saver() -> BootstrapResult<()> {
   let (tx, mut rx) = mpsc::channel(10);
   INVENTORY_ITEM_SAVE_TX
@@ -70,12 +65,13 @@ pub async fn start_inventory_item_saver() -> BootstrapResult<()> {
   tokio::task::spawn(async move {
     let mut last_save_time = Instant::now();
     let mut items_to_save = Vec::new();
-    let mut unique_user_ids = Vec::default();
+    let mut stored_txs = Vec::new();
 
     loop {
       let res = tokio::time::timeout(tokio::time::Duration::from_millis(1350), rx.recv()).await;
-      if let Ok(Some(item)) = res {
-        items_to_save.push(item);
+      if let Ok(Some((items, stored_tx))) = res {
+        stored_txs.push((stored_tx, items.len()));
+        items_to_save.extend(items);
       }
 
       if items_to_save.is_empty() {
@@ -83,21 +79,19 @@ pub async fn start_inventory_item_saver() -> BootstrapResult<()> {
       }
 
       if last_save_time.elapsed().as_secs() >= 2 || items_to_save.len() >= 100 {
-        unique_user_ids.extend(items_to_save.iter().map(|item| item.user_id));
-        unique_user_ids.sort_unstable();
-        unique_user_ids.dedup();
-
         match crate::db::insert_inventory_items(&items_to_save).await {
           Err(err) => {
             error!("Failed to save inventory items: {err:?}");
           },
-          Ok(_) => {
+          Ok(stored) => {
+            let mut stored = stored.into_iter();
+            for (stored_tx, count) in stored_txs.drain(..) {
+              let _ = stored_tx.send(stored.by_ref().take(count).collect());
+            }
             items_to_save.clear();
             last_save_time = Instant::now();
           },
         }
-
-        tokio::task::spawn(check_inventory_space(std::mem::take(&mut unique_user_ids)));
       }
     }
   });
@@ -156,6 +150,16 @@ pub async fn start_mining(
     ));
   }
 
+  let roll_modifiers = load_roll_modifiers(user_id).await?;
+
+  let (mut achievement_tracker, unlocked_achievements) =
+    MiningAchievementTracker::start(user_id, location_name)
+      .await
+      .map_err(|err| {
+        error!("Failed to load achievement progress: {err}");
+        Status::internal("Internal DB error")
+      })?;
+
   let (stop_tx, mut stop_rx) = mpsc::channel(1);
   let session = MiningSession {
     token: session_token,
@@ -174,12 +178,20 @@ pub async fn start_mining(
   info!("User {user_id} started mining at location {location_name}");
 
   tokio::task::spawn(async move {
-    let millis_until_next_loot = 8200u32;
+    let millis_until_next_loot =
+      (8200. / get_prestige_loot_speed_multiplier(roll_modifiers.prestige_level)) as u32;
+    // Loot is sent once it's been saved, which takes a variable amount of time, so ticks are
+    // scheduled independently of it.
+    let period = Duration::from_millis(millis_until_next_loot as _);
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     if tx
       .send(Ok(StartMiningResponse {
         loot: None,
         millis_until_next_loot,
+        loot_stored: false,
+        unlocked_achievements,
       }))
       .await
       .is_err()
@@ -187,22 +199,15 @@ pub async fn start_mining(
       return;
     }
 
-    loop {
-      tokio::time::sleep(Duration::from_millis(millis_until_next_loot as _)).await;
+    let mut unrecorded_ticks = 0;
+    let mut unrecorded_since = Instant::now();
+    'mining: loop {
+      ticks.tick().await;
 
       if let Ok(stop_reason) = stop_rx.try_recv() {
         match stop_reason {
           StopMiningReason::Manual => info!("User {user_id} stopped mining manually"),
-          StopMiningReason::InventoryFull => {
-            warn!("User {user_id} stopped mining due to full inventory");
-            let _ = tx
-              .send(Err(Status::resource_exhausted(
-                "Inventory is full; mining halted.  Upgrade storage capacity or remove items from \
-                 inventory before continuing.",
-              )))
-              .await;
-            break;
-          },
+          StopMiningReason::Prestige => info!("User {user_id} stopped mining to prestige"),
         }
         break;
       }
@@ -216,40 +221,118 @@ pub async fn start_mining(
         },
       }
 
-      let loot = loot_table.roll(&mut rng);
+      unrecorded_ticks += 1;
+      if unrecorded_ticks >= MINING_TIME_RECORD_INTERVAL_TICKS {
+        record_mining_time(
+          user_id,
+          location_name,
+          &mut unrecorded_ticks,
+          &mut unrecorded_since,
+        )
+        .await;
+      }
 
-      let res = inventory_item_save_tx()
-        .send(NewInventoryItem {
+      // Equipment can find more than one item per tick.  They're all saved together.
+      let loot: Vec<_> = (0..roll_modifiers.gen_roll_count(&mut rng))
+        .map(|_| loot_table.roll(&mut rng, &roll_modifiers))
+        .collect();
+      let new_items = loot
+        .iter()
+        .map(|loot| NewInventoryItem {
+          id: Uuid::parse_str(&loot.item_uuid).expect("Rolled loot has an invalid UUID"),
           user_id,
           item_id: loot.item_type_id,
           quality: loot.quality,
           value: loot.value,
           modifiers: None, // TODO
+          reason: InventoryChangeReason::Mined { location_name },
         })
-        .await;
-      if res.is_err() {
-        error!("Failed to save inventory item; channel closed");
-        break;
-      }
+        .collect();
 
-      if tx
-        .send(Ok(StartMiningResponse {
-          loot: Some(loot),
-          millis_until_next_loot,
-        }))
+      let (stored_tx, stored_rx) = oneshot::channel();
+      if inventory_item_save_tx()
+        .send((new_items, stored_tx))
         .await
         .is_err()
       {
+        error!("Failed to save inventory items; channel closed");
+        break;
+      }
+      let Ok(stored_uuids) = stored_rx.await else {
+        error!("Failed to save inventory items; saver dropped them");
+        break;
+      };
+
+      let mut inventory_full = false;
+      for (mut loot, stored_uuid) in loot.into_iter().zip(stored_uuids) {
+        // Stackable items are merged into the user's stack, which keeps its own UUID
+        let loot_stored = stored_uuid.is_some();
+        if let Some(stored_uuid) = stored_uuid {
+          loot.item_uuid = stored_uuid.to_string();
+        }
+
+        let unlocked_achievements = if loot_stored {
+          achievement_tracker.item_stored(&loot).await
+        } else {
+          Vec::new()
+        };
+
+        if tx
+          .send(Ok(StartMiningResponse {
+            loot: Some(loot),
+            millis_until_next_loot,
+            loot_stored,
+            unlocked_achievements,
+          }))
+          .await
+          .is_err()
+        {
+          break 'mining;
+        }
+        inventory_full |= !loot_stored;
+      }
+
+      if inventory_full {
+        warn!("User {user_id} stopped mining due to full inventory");
+        let _ = tx
+          .send(Err(Status::resource_exhausted(
+            "Inventory is full; mining halted.  Upgrade storage capacity or remove items from \
+             inventory before continuing.",
+          )))
+          .await;
         break;
       }
     }
 
+    record_mining_time(
+      user_id,
+      location_name,
+      &mut unrecorded_ticks,
+      &mut unrecorded_since,
+    )
+    .await;
     drop(drop_handle);
   });
 
   Ok(ReceiverStream::new(rx))
 }
 
+async fn record_mining_time(
+  user_id: i32,
+  location_name: &str,
+  unrecorded_ticks: &mut i64,
+  unrecorded_since: &mut Instant,
+) {
+  let millis = unrecorded_since.elapsed().as_millis() as i64;
+  if let Err(err) =
+    crate::db::record_mining_time(user_id, location_name, *unrecorded_ticks, millis).await
+  {
+    error!("Failed to record mining time for user {user_id}: {err}");
+  }
+  *unrecorded_ticks = 0;
+  *unrecorded_since = Instant::now();
+}
+
 pub fn stop_mining(user_id: i32, reason: StopMiningReason, session_token: Option<Uuid>) {
   let removed = ACTIVE_MINING_SESSIONS.remove_if(&user_id, |_, session| match session_token {
     Some(token) => session.token == token,
@@ -257,7 +340,7 @@ pub fn stop_mining(user_id: i32, reason: StopMiningReason, session_token: Option
   });
 
   if let Some((_uid, session)) = removed {
-    let _ = session.stop_tx.send(reason);
+    let _ = session.stop_tx.try_send(reason);
     crate::metrics::game::active_mine_sessions(session.location_name).dec();
   }
 }
@@ -1,3 +1,9 @@
+pub mod achievements;
+pub mod debit;
+pub mod equipment;
 pub mod items;
 pub mod mine;
+pub mod prestige;
+pub mod quests;
+pub mod stacks;
 pub mod upgrades;
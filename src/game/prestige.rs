@@ -0,0 +1,116 @@
+use tonic::Status;
+
+use crate::{
+  db::{clear_user_inventory, pool, InventoryChangeReason},
+  protos::PrestigeInfo,
+};
+
+use super::mine::{stop_mining, StopMiningReason};
+
+/// Storage level needed to prestige the first time.  Each prestige after that needs
+/// `PRESTIGE_STORAGE_LEVEL_STEP` more.
+pub const PRESTIGE_BASE_STORAGE_LEVEL: u32 = 10;
+pub const PRESTIGE_STORAGE_LEVEL_STEP: u32 = 5;
+
+pub fn get_prestige_required_storage_level(prestige_level: u32) -> u32 {
+  PRESTIGE_BASE_STORAGE_LEVEL + prestige_level * PRESTIGE_STORAGE_LEVEL_STEP
+}
+
+pub fn get_prestige_value_multiplier(prestige_level: u32) -> f32 {
+  1. + 0.25 * prestige_level as f32
+}
+
+pub fn get_prestige_loot_speed_multiplier(prestige_level: u32) -> f32 {
+  1. + 0.1 * prestige_level as f32
+}
+
+pub fn get_prestige_info(prestige_level: u32) -> PrestigeInfo {
+  PrestigeInfo {
+    prestige_level,
+    required_storage_level: get_prestige_required_storage_level(prestige_level),
+    value_multiplier: get_prestige_value_multiplier(prestige_level),
+    loot_speed_multiplier: get_prestige_loot_speed_multiplier(prestige_level),
+  }
+}
+
+/// Empties the user's inventory and resets their base upgrades, then raises their prestige level.
+/// Returns their new prestige bonuses.
+pub(crate) async fn prestige(user_id: i32) -> Result<PrestigeInfo, Status> {
+  let mut txn = pool().begin().await.map_err(|err| {
+    error!("Failed to start transaction: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  // The base is locked before the inventory, same as for upgrades
+  let storage_level = sqlx::query_scalar!(
+    "SELECT storage_level FROM bases WHERE user_id = $1 FOR UPDATE",
+    user_id,
+  )
+  .fetch_optional(&mut *txn)
+  .await
+  .map(|row| row.unwrap_or(0))
+  .map_err(|err| {
+    error!("Failed to fetch inventory upgrade level: {err}");
+    Status::internal("Internal DB error")
+  })? as u32;
+  let prestige_level = sqlx::query_scalar!(
+    "SELECT prestige_level FROM users WHERE id = $1 FOR UPDATE",
+    user_id,
+  )
+  .fetch_one(&mut *txn)
+  .await
+  .map_err(|err| {
+    error!("Failed to fetch prestige level: {err}");
+    Status::internal("Internal DB error")
+  })? as u32;
+
+  let required_storage_level = get_prestige_required_storage_level(prestige_level);
+  if storage_level < required_storage_level {
+    return Err(Status::failed_precondition(format!(
+      "Storage must be upgraded to level {required_storage_level} to prestige"
+    )));
+  }
+
+  let reason = InventoryChangeReason::Prestige {
+    level: prestige_level + 1,
+  };
+  clear_user_inventory(&mut txn, user_id, &reason)
+    .await
+    .map_err(|err| {
+      error!("Failed to clear inventory for prestige: {err}");
+      Status::internal("Internal DB error")
+    })?;
+
+  sqlx::query!(
+    "UPDATE bases SET storage_level = 0 WHERE user_id = $1",
+    user_id,
+  )
+  .execute(&mut *txn)
+  .await
+  .map_err(|err| {
+    error!("Failed to reset base upgrades: {err}");
+    Status::internal("Internal DB error")
+  })?;
+  sqlx::query!(
+    "UPDATE users SET prestige_level = prestige_level + 1 WHERE id = $1",
+    user_id,
+  )
+  .execute(&mut *txn)
+  .await
+  .map_err(|err| {
+    error!("Failed to update prestige level: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  txn.commit().await.map_err(|err| {
+    error!("Failed to commit transaction: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  // Mining sessions keep the bonuses they started with, so the current one is stopped
+  stop_mining(user_id, StopMiningReason::Prestige, None);
+
+  info!("User {user_id} prestiged to level {}", prestige_level + 1);
+
+  Ok(get_prestige_info(prestige_level + 1))
+}
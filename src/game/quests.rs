@@ -0,0 +1,340 @@
+use chrono::{Datelike, NaiveDate, NaiveTime, Utc};
+use foundations::BootstrapResult;
+use fxhash::FxHashSet;
+use once_cell::sync::OnceCell;
+use rand::{rngs::OsRng, seq::SliceRandom, SeedableRng};
+use serde::Deserialize;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::{
+  db::{
+    debit_user_inventory, get_user_completed_quests, get_user_items_mined_since,
+    get_user_prestige_level, get_user_unlocked_item_quality, insert_inventory_items_txn,
+    insert_quest_completion, pool, InventoryChangeReason, NewInventoryItem,
+  },
+  protos::{
+    daily_quest, CompleteQuestRequest, CompleteQuestResponse, DailyQuest, GetDailyQuestsResponse,
+    Item, ItemCost, MineItemsObjective,
+  },
+};
+
+use super::{
+  debit::DebitStrategy,
+  items::{get_item_id_by_name, item_descriptors, mine_locations, LootTable, RollModifiers},
+};
+
+/// Number of quests picked from the pool each day
+pub const DAILY_QUEST_COUNT: usize = 3;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum QuestObjective {
+  Deliver {
+    item: String,
+    total_quality: f32,
+  },
+  /// Items mined today at `location`, or at all locations combined if it's not set
+  MineItems {
+    location: Option<String>,
+    count: i64,
+  },
+}
+
+#[derive(Deserialize)]
+pub struct QuestReward {
+  pub rolls: u32,
+  pub loot_table: LootTable,
+}
+
+#[derive(Deserialize)]
+pub struct QuestDefinition {
+  pub name: String,
+  pub display_name: String,
+  pub description: String,
+  pub objective: QuestObjective,
+  pub reward: QuestReward,
+}
+
+impl QuestDefinition {
+  fn deliver_cost(&self) -> Option<ItemCost> {
+    match &self.objective {
+      QuestObjective::Deliver {
+        item,
+        total_quality,
+      } => Some(ItemCost {
+        item_id: get_item_id_by_name(item),
+        total_quality: *total_quality,
+      }),
+      QuestObjective::MineItems { .. } => None,
+    }
+  }
+
+  pub fn to_proto(&self, progress: f32, completed: bool) -> DailyQuest {
+    let objective = match &self.objective {
+      QuestObjective::Deliver { .. } =>
+        daily_quest::Objective::Deliver(self.deliver_cost().unwrap()),
+      QuestObjective::MineItems { location, count } =>
+        daily_quest::Objective::MineItems(MineItemsObjective {
+          location_name: location.clone(),
+          count: *count as u32,
+        }),
+    };
+    DailyQuest {
+      name: self.name.clone(),
+      display_name: self.display_name.clone(),
+      description: self.description.clone(),
+      objective: Some(objective),
+      progress,
+      completed,
+    }
+  }
+}
+
+static QUESTS: OnceCell<Vec<QuestDefinition>> = OnceCell::new();
+
+pub fn quests() -> &'static [QuestDefinition] { QUESTS.get().expect("Quests not initialized") }
+
+/// Must be called after `init_loot_tables`.
+pub fn init_quests() -> BootstrapResult<()> {
+  let quests: Vec<QuestDefinition> = serde_yaml::from_str(include_str!("quests.yml"))?;
+
+  let mut names = FxHashSet::default();
+  for quest in &quests {
+    if !names.insert(quest.name.as_str()) {
+      return Err(anyhow::anyhow!("Duplicate quest name {}", quest.name));
+    }
+    match &quest.objective {
+      QuestObjective::Deliver { item, .. } => {
+        if !item_descriptors()
+          .iter()
+          .any(|descriptor| &descriptor.name == item)
+        {
+          return Err(anyhow::anyhow!(
+            "Quest {} refers to unknown item {item}",
+            quest.name
+          ));
+        }
+      },
+      QuestObjective::MineItems {
+        location: Some(location),
+        ..
+      } => {
+        if !mine_locations()
+          .iter()
+          .any(|loc| &loc.descriptor.name == location)
+        {
+          return Err(anyhow::anyhow!(
+            "Quest {} refers to unknown mine location {location}",
+            quest.name
+          ));
+        }
+      },
+      QuestObjective::MineItems { location: None, .. } => {},
+    }
+  }
+  if quests.len() < DAILY_QUEST_COUNT {
+    return Err(anyhow::anyhow!(
+      "Need at least {DAILY_QUEST_COUNT} quests in the pool, found {}",
+      quests.len()
+    ));
+  }
+
+  let count = quests.len();
+  QUESTS
+    .set(quests)
+    .map_err(|_| anyhow::anyhow!("Quests already initialized"))?;
+  info!("Initialized {count} quests");
+
+  Ok(())
+}
+
+/// Picks `DAILY_QUEST_COUNT` entries from `pool` with `day` as the seed, so that the same ones are
+/// picked every time for a given day.
+fn pick_daily<T>(pool: &[T], day: NaiveDate) -> Vec<&T> {
+  let mut rng = pcg_rand::Pcg64::seed_from_u64(day.num_days_from_ce() as u64);
+  pool.choose_multiple(&mut rng, DAILY_QUEST_COUNT).collect()
+}
+
+/// The quests offered on `day`, which are the same for every user.
+pub fn daily_quests(day: NaiveDate) -> Vec<&'static QuestDefinition> { pick_daily(quests(), day) }
+
+fn today() -> NaiveDate { Utc::now().date_naive() }
+
+pub(crate) async fn get_daily_quests(user_id: i32) -> Result<GetDailyQuestsResponse, Status> {
+  let day = today();
+  let quests = daily_quests(day);
+
+  let completed = get_user_completed_quests(user_id, day)
+    .await
+    .map_err(|err| {
+      error!("Failed to fetch completed quests for user {user_id}: {err}");
+      Status::internal("Internal DB error")
+    })?;
+  let items_mined = get_user_items_mined_since(user_id, day.and_time(NaiveTime::MIN))
+    .await
+    .map_err(|err| {
+      error!("Failed to fetch items mined today for user {user_id}: {err}");
+      Status::internal("Internal DB error")
+    })?;
+  let deliver_item_ids: Vec<i32> = quests
+    .iter()
+    .filter_map(|quest| quest.deliver_cost())
+    .map(|cost| cost.item_id as i32)
+    .collect();
+  let item_quality = get_user_unlocked_item_quality(user_id, &deliver_item_ids)
+    .await
+    .map_err(|err| {
+      error!("Failed to fetch item quality for user {user_id}: {err}");
+      Status::internal("Internal DB error")
+    })?;
+
+  let quests = quests
+    .into_iter()
+    .map(|quest| {
+      let progress = match &quest.objective {
+        QuestObjective::Deliver { .. } => {
+          let item_id = quest.deliver_cost().unwrap().item_id as i32;
+          item_quality.get(&item_id).copied().unwrap_or(0.) as f32
+        },
+        QuestObjective::MineItems {
+          location: Some(location),
+          ..
+        } => items_mined.get(location).copied().unwrap_or(0) as f32,
+        QuestObjective::MineItems { location: None, .. } =>
+          items_mined.values().sum::<i64>() as f32,
+      };
+      quest.to_proto(progress, completed.contains(&quest.name))
+    })
+    .collect();
+
+  Ok(GetDailyQuestsResponse {
+    quests,
+    resets_at: (day + chrono::Days::new(1))
+      .and_time(NaiveTime::MIN)
+      .and_utc()
+      .timestamp(),
+  })
+}
+
+/// Completes one of today's quests for the user.  Delivered items are consumed and the rewards are
+/// added to the inventory in the same transaction, so the quest fails without consuming anything
+/// if the rewards don't fit.
+pub(crate) async fn complete_quest(
+  user_id: i32,
+  req: CompleteQuestRequest,
+) -> Result<CompleteQuestResponse, Status> {
+  let day = today();
+  let Some(quest) = daily_quests(day)
+    .into_iter()
+    .find(|quest| quest.name == req.quest_name)
+  else {
+    return Err(Status::not_found("That quest isn't available today"));
+  };
+  let strategy = DebitStrategy::from_selection(req.debit_selection)?;
+
+  if let QuestObjective::MineItems { location, count } = &quest.objective {
+    let items_mined = get_user_items_mined_since(user_id, day.and_time(NaiveTime::MIN))
+      .await
+      .map_err(|err| {
+        error!("Failed to fetch items mined today for user {user_id}: {err}");
+        Status::internal("Internal DB error")
+      })?;
+    let mined = match location {
+      Some(location) => items_mined.get(location).copied().unwrap_or(0),
+      None => items_mined.values().sum(),
+    };
+    if mined < *count {
+      return Err(Status::failed_precondition(format!(
+        "Only {mined} of {count} items mined today"
+      )));
+    }
+  }
+
+  let prestige_level = get_user_prestige_level(user_id).await.map_err(|err| {
+    error!("Failed to get prestige level: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  let mut txn = pool().begin().await.map_err(|err| {
+    error!("Failed to start transaction: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  let inserted = insert_quest_completion(&mut txn, user_id, day, &quest.name)
+    .await
+    .map_err(|err| {
+      error!("Failed to insert quest completion: {err}");
+      Status::internal("Internal DB error")
+    })?;
+  if !inserted {
+    return Err(Status::failed_precondition(
+      "That quest has already been completed today",
+    ));
+  }
+
+  let reason = InventoryChangeReason::Quest { name: &quest.name };
+  let debits = match quest.deliver_cost() {
+    Some(cost) =>
+      debit_user_inventory(&mut txn, user_id, &[cost], &strategy, false, &reason).await?,
+    None => Vec::new(),
+  };
+
+  // Equipment only helps with mining
+  let roll_modifiers = RollModifiers {
+    prestige_level,
+    ..Default::default()
+  };
+  let mut rng = pcg_rand::Pcg64::from_rng(OsRng).unwrap();
+  let mut rewards: Vec<Item> = (0..quest.reward.rolls)
+    .map(|_| quest.reward.loot_table.roll(&mut rng, &roll_modifiers))
+    .collect();
+  let new_items: Vec<NewInventoryItem> = rewards
+    .iter()
+    .map(|item| NewInventoryItem {
+      id: Uuid::parse_str(&item.item_uuid).expect("Rolled loot has an invalid UUID"),
+      user_id,
+      item_id: item.item_type_id,
+      quality: item.quality,
+      value: item.value,
+      modifiers: None, // TODO
+      reason: reason.clone(),
+    })
+    .collect();
+  let stored = insert_inventory_items_txn(&mut txn, &new_items)
+    .await
+    .map_err(|err| {
+      error!("Failed to insert quest rewards: {err}");
+      Status::internal("Internal DB error")
+    })?;
+  if stored.contains(&None) {
+    return Err(Status::resource_exhausted(
+      "Not enough inventory space for the quest rewards",
+    ));
+  }
+  for (reward, stored_uuid) in rewards.iter_mut().zip(stored.into_iter().flatten()) {
+    reward.item_uuid = stored_uuid.to_string();
+  }
+
+  txn.commit().await.map_err(|err| {
+    error!("Failed to commit transaction: {err}");
+    Status::internal("Internal DB error")
+  })?;
+
+  info!("User {user_id} completed quest {}", quest.name);
+
+  Ok(CompleteQuestResponse { debits, rewards })
+}
+
+#[test]
+fn test_pick_daily() {
+  let pool: Vec<u32> = (0..20).collect();
+  let day = NaiveDate::from_ymd_opt(2024, 5, 28).unwrap();
+
+  let picked = pick_daily(&pool, day);
+  assert_eq!(picked.len(), DAILY_QUEST_COUNT);
+  assert_eq!(picked, pick_daily(&pool, day));
+  let unique: FxHashSet<_> = picked.iter().collect();
+  assert_eq!(unique.len(), DAILY_QUEST_COUNT);
+}
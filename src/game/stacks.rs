@@ -0,0 +1,298 @@
+use foundations::BootstrapResult;
+use once_cell::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::conf::Settings;
+
+static QUALITY_HISTOGRAM_BUCKETS: OnceCell<usize> = OnceCell::new();
+
+pub fn init_quality_histograms(settings: &Settings) -> BootstrapResult<()> {
+  let buckets = settings.inventory.quality_histogram_buckets;
+  if buckets == 0 {
+    return Err(anyhow::anyhow!(
+      "inventory.quality_histogram_buckets must be at least 1"
+    ));
+  }
+
+  QUALITY_HISTOGRAM_BUCKETS
+    .set(buckets)
+    .map_err(|_| anyhow::anyhow!("Quality histograms already initialized"))?;
+  Ok(())
+}
+
+/// Number of equal-width buckets that quality histograms divide [0, 1) into.
+pub fn quality_histogram_buckets() -> usize {
+  *QUALITY_HISTOGRAM_BUCKETS
+    .get()
+    .expect("Quality histograms not initialized")
+}
+
+pub fn quality_bucket(quality: f32, buckets: usize) -> usize {
+  ((quality.max(0.) * buckets as f32) as usize).min(buckets - 1)
+}
+
+/// Quality that stacked items in the lowest histogram bucket are counted as when they're spent.
+/// The bucket's lower bound is zero, which would make them unspendable.
+pub const MIN_STACKED_ITEM_QUALITY: f32 = 0.01;
+
+fn quality_bucket_lower_bound(bucket_ix: usize, buckets: usize) -> f32 {
+  bucket_ix as f32 / buckets as f32
+}
+
+/// Quality that a stacked item in the given bucket is counted as when it's spent.
+fn quality_bucket_spent_quality(bucket_ix: usize, buckets: usize) -> f32 {
+  quality_bucket_lower_bound(bucket_ix, buckets).max(MIN_STACKED_ITEM_QUALITY)
+}
+
+/// The total quality that the items counted in `histogram` are counted as when they're spent: the
+/// lower bound of each item's bucket, and at least `MIN_STACKED_ITEM_QUALITY`.
+pub fn histogram_min_quality(histogram: &[i32]) -> f64 {
+  histogram
+    .iter()
+    .enumerate()
+    .map(|(bucket_ix, &count)| {
+      count as f64 * quality_bucket_spent_quality(bucket_ix, histogram.len()) as f64
+    })
+    .sum()
+}
+
+/// The most total quality that the items counted in `histogram` can have.
+fn histogram_max_quality(histogram: &[i32]) -> f64 {
+  histogram
+    .iter()
+    .enumerate()
+    .map(|(bucket_ix, &count)| {
+      count as f64 * quality_bucket_lower_bound(bucket_ix + 1, histogram.len()) as f64
+    })
+    .sum()
+}
+
+/// Moves the counts of a histogram into a different number of buckets, by the lower bound of each
+/// of its buckets so that items are never counted as more quality than they might have.
+pub fn rebucket_histogram(histogram: &[i32], buckets: usize) -> Vec<i32> {
+  let mut rebucketed = vec![0; buckets];
+  for (bucket_ix, count) in histogram.iter().enumerate() {
+    rebucketed[bucket_ix * buckets / histogram.len()] += count;
+  }
+  rebucketed
+}
+
+/// Number of inventory slots taken up by `count` of an item.  Items that aren't stackable take up a
+/// slot each.
+pub fn stack_slots(count: i32, stack_size: Option<i32>) -> i32 {
+  let stack_size = stack_size.unwrap_or(1).max(1);
+  (count + stack_size - 1) / stack_size
+}
+
+/// A change to the number, total quality, and total value of an item in a user's inventory, along
+/// with the change to each of its quality histogram buckets.
+pub struct HistogramDelta {
+  pub count: i32,
+  pub total_quality: f64,
+  pub total_value: f64,
+  pub quality_histogram: Vec<i32>,
+}
+
+impl HistogramDelta {
+  pub fn new(buckets: usize) -> Self {
+    HistogramDelta {
+      count: 0,
+      total_quality: 0.,
+      total_value: 0.,
+      quality_histogram: vec![0; buckets],
+    }
+  }
+
+  pub fn add(&mut self, quality: f32, value: f32) {
+    self.count += 1;
+    self.total_quality += quality as f64;
+    self.total_value += value as f64;
+    let buckets = self.quality_histogram.len();
+    self.quality_histogram[quality_bucket(quality, buckets)] += 1;
+  }
+
+  pub fn remove(&mut self, quality: f32, value: f32) {
+    self.count -= 1;
+    self.total_quality -= quality as f64;
+    self.total_value -= value as f64;
+    let buckets = self.quality_histogram.len();
+    self.quality_histogram[quality_bucket(quality, buckets)] -= 1;
+  }
+
+  pub fn remove_all(&mut self, removed: &HistogramDelta) {
+    self.count -= removed.count;
+    self.total_quality -= removed.total_quality;
+    self.total_value -= removed.total_value;
+    for (bucket, removed) in self
+      .quality_histogram
+      .iter_mut()
+      .zip(&removed.quality_histogram)
+    {
+      *bucket -= removed;
+    }
+  }
+}
+
+pub struct ItemStack {
+  pub id: Uuid,
+  pub item_id: i32,
+  pub count: i32,
+  pub total_quality: f64,
+  pub total_value: f64,
+  pub quality_histogram: Vec<i32>,
+  /// Locked stacks aren't consumed by debits.  Only changed with `db::set_item_locked`.
+  pub locked: bool,
+}
+
+impl ItemStack {
+  pub fn new(item_id: i32, buckets: usize) -> Self {
+    ItemStack {
+      id: Uuid::new_v4(),
+      item_id,
+      count: 0,
+      total_quality: 0.,
+      total_value: 0.,
+      quality_histogram: vec![0; buckets],
+      locked: false,
+    }
+  }
+
+  pub fn add(&mut self, quality: f32, value: f32) {
+    self.count += 1;
+    self.total_quality += quality as f64;
+    self.total_value += value as f64;
+    let buckets = self.quality_histogram.len();
+    self.quality_histogram[quality_bucket(quality, buckets)] += 1;
+  }
+
+  /// The total quality that the stack's items are counted as when they're spent.
+  pub fn min_total_quality(&self) -> f64 { histogram_min_quality(&self.quality_histogram) }
+
+  /// Removes items from the stack, lowest quality first, until the removed items count for at
+  /// least `quality` total quality or nothing more can be taken.  Returns what was removed.
+  ///
+  /// Individual qualities aren't stored, so each item counts as the lower bound of its histogram
+  /// bucket, or `MIN_STACKED_ITEM_QUALITY` in the lowest bucket.  The removed totals use that same
+  /// quality, with the value `item_value` gives for it, except that the stack's remaining totals
+  /// are kept within what its remaining items could add up to.  `histogram_min_quality` of the
+  /// removed histogram is always the quality that was counted.
+  pub fn take_lowest(&mut self, quality: f32, item_value: impl Fn(f32) -> f32) -> HistogramDelta {
+    let buckets = self.quality_histogram.len();
+    let mut removed = HistogramDelta::new(buckets);
+    for (bucket_ix, bucket_count) in self.quality_histogram.iter_mut().enumerate() {
+      if removed.total_quality >= quality as f64 {
+        break;
+      }
+
+      let item_quality = quality_bucket_spent_quality(bucket_ix, buckets);
+      let needed = ((quality as f64 - removed.total_quality) / item_quality as f64).ceil() as i32;
+      let taken = needed.min(*bucket_count);
+      *bucket_count -= taken;
+      removed.quality_histogram[bucket_ix] = taken;
+      removed.count += taken;
+      removed.total_quality += taken as f64 * item_quality as f64;
+      removed.total_value += taken as f64 * item_value(item_quality) as f64;
+    }
+
+    self.count -= removed.count;
+    if self.count == 0 {
+      // Whatever's left over from approximating removed qualities goes with the last items
+      removed.total_quality = self.total_quality;
+      removed.total_value = self.total_value;
+      self.total_quality = 0.;
+      self.total_value = 0.;
+    } else {
+      let remaining_quality = (self.total_quality - removed.total_quality)
+        .min(histogram_max_quality(&self.quality_histogram))
+        .max(0.);
+      removed.total_quality = self.total_quality - remaining_quality;
+      removed.total_value = removed.total_value.min(self.total_value);
+      self.total_quality = remaining_quality;
+      self.total_value -= removed.total_value;
+    }
+
+    removed
+  }
+}
+
+#[test]
+fn test_item_stack_take_lowest() {
+  let mut stack = ItemStack::new(0, 32);
+  for quality in [0.9, 0.1, 0.1, 0.5] {
+    stack.add(quality, quality * 2.);
+  }
+  assert_eq!(stack.count, 4);
+  assert_eq!(stack_slots(stack.count, Some(3)), 2);
+
+  // Both 0.1 items are taken before the 0.5 one
+  let removed = stack.take_lowest(0.15, |quality| quality * 2.);
+  let low_lower_bound = quality_bucket_lower_bound(quality_bucket(0.1, 32), 32) as f64;
+  assert_eq!(removed.count, 2);
+  assert!((removed.total_quality - low_lower_bound * 2.).abs() < 1e-6);
+  assert!((histogram_min_quality(&removed.quality_histogram) - removed.total_quality).abs() < 1e-6);
+  assert_eq!(removed.quality_histogram[quality_bucket(0.1, 32)], 2);
+  assert_eq!(stack.count, 2);
+  assert_eq!(stack.quality_histogram[quality_bucket(0.1, 32)], 0);
+  assert_eq!(stack.quality_histogram[quality_bucket(0.5, 32)], 1);
+  assert!((stack.total_value - (3.2 - low_lower_bound * 4.)).abs() < 1e-4);
+
+  let removed = stack.take_lowest(10., |quality| quality * 2.);
+  assert_eq!(removed.count, 2);
+  assert_eq!(stack.count, 0);
+  assert_eq!(stack.total_quality, 0.);
+
+  assert_eq!(rebucket_histogram(&[1, 2, 3, 4], 2), vec![3, 7]);
+  assert_eq!(rebucket_histogram(&[1, 2, 3], 2), vec![3, 3]);
+  // Splitting buckets keeps each one's items at its lower end
+  assert_eq!(rebucket_histogram(&[1, 2], 4), vec![1, 0, 2, 0]);
+}
+
+#[test]
+fn test_item_stack_take_lowest_bucket_lower_bound() {
+  // Every item is at the very bottom of its bucket, so counting them at the midpoint would take
+  // too few
+  let bottom = quality_bucket_lower_bound(3, 32);
+  let mut stack = ItemStack::new(0, 32);
+  stack.add(0.01, 0.01);
+  for _ in 0..10 {
+    stack.add(bottom, bottom);
+  }
+  assert!(
+    (stack.min_total_quality() - (MIN_STACKED_ITEM_QUALITY + bottom * 10.) as f64).abs() < 1e-6
+  );
+
+  let removed = stack.take_lowest(0.3, |quality| quality);
+  // The item in the lowest bucket is taken first, at the minimum quality
+  assert_eq!(removed.count, 5);
+  assert_eq!(removed.quality_histogram[0], 1);
+  assert!(MIN_STACKED_ITEM_QUALITY + 4. * bottom >= 0.3);
+  assert!(histogram_min_quality(&removed.quality_histogram) >= 0.3);
+  assert_eq!(stack.quality_histogram[0], 0);
+  assert!(stack.total_quality >= stack.min_total_quality());
+  assert!(stack.total_quality <= histogram_max_quality(&stack.quality_histogram));
+}
+
+#[test]
+fn test_item_stack_take_lowest_bottom_bucket() {
+  let mut stack = ItemStack::new(0, 32);
+  for _ in 0..5 {
+    stack.add(0.015, 0.5);
+  }
+  assert_eq!(stack.quality_histogram[0], 5);
+  assert!((stack.min_total_quality() - MIN_STACKED_ITEM_QUALITY as f64 * 5.).abs() < 1e-6);
+
+  let removed = stack.take_lowest(0.025, |_| 0.5);
+  assert_eq!(removed.count, 3);
+  assert!((removed.total_quality - MIN_STACKED_ITEM_QUALITY as f64 * 3.).abs() < 1e-6);
+  assert!((histogram_min_quality(&removed.quality_histogram) - removed.total_quality).abs() < 1e-6);
+  assert_eq!(stack.count, 2);
+  assert!(stack.total_quality >= stack.min_total_quality());
+  assert!(stack.total_quality <= histogram_max_quality(&stack.quality_histogram));
+
+  // Asking for more than the stack has takes everything that's left
+  let removed = stack.take_lowest(1., |_| 0.5);
+  assert_eq!(removed.count, 2);
+  assert!(histogram_min_quality(&removed.quality_histogram) < 1.);
+  assert_eq!(stack.count, 0);
+  assert_eq!(stack.total_quality, 0.);
+}
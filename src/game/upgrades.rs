@@ -1,15 +1,24 @@
 use tonic::Status;
 
 use crate::{
-  db::{debit_user_inventory, get_user_upgrades, pool},
-  protos::{ItemCost, UpgradeBaseRequest, UpgradeType, Upgrades},
+  db::{debit_user_inventory, get_user_upgrades, pool, InventoryChangeReason},
+  protos::{
+    DebitSelection, ItemCost, ItemCostDebit, PreviewUpgradeRequest, UpgradeBaseRequest,
+    UpgradeBaseResponse, UpgradeType,
+  },
 };
 
-use super::items::get_item_id_by_name;
+use super::{
+  achievements::check_storage_achievements, debit::DebitStrategy, items::get_item_id_by_name,
+};
 
 pub const BASE_INVENTORY_SIZE: u32 = 5_000;
 pub const INVENTORY_CAPACITY_PER_UPGRADE: u32 = 1_000;
 
+pub fn get_inventory_capacity(storage_level: i32) -> i32 {
+  BASE_INVENTORY_SIZE as i32 + storage_level * INVENTORY_CAPACITY_PER_UPGRADE as i32
+}
+
 pub fn get_inventory_upgrade_cost(level: u32) -> [ItemCost; 3] {
   let base_cost = (3. * (level + 1) as f32) * 1.18_f32.powf(0.33 * level as f32);
 
@@ -29,7 +38,14 @@ pub fn get_inventory_upgrade_cost(level: u32) -> [ItemCost; 3] {
   ]
 }
 
-pub async fn upgrade_inventory_storage(user_id: i32) -> Result<(), Status> {
+/// Upgrades the user's storage, paying for it with items chosen by `strategy`.  With `dry_run`, the
+/// transaction is rolled back instead so nothing changes, and missing items are reported in the
+/// returned debits rather than failing.  Returns the items consumed.
+pub async fn upgrade_inventory_storage(
+  user_id: i32,
+  strategy: &DebitStrategy,
+  dry_run: bool,
+) -> Result<Vec<ItemCostDebit>, Status> {
   let mut txn = pool().begin().await.map_err(|err| {
     error!("Failed to start transaction: {err}");
     Status::internal("Internal DB error")
@@ -61,7 +77,11 @@ pub async fn upgrade_inventory_storage(user_id: i32) -> Result<(), Status> {
 
   let upgrade_cost = get_inventory_upgrade_cost(inventory_upgrade_level);
 
-  debit_user_inventory(&mut txn, user_id, &upgrade_cost).await?;
+  let reason = InventoryChangeReason::StorageUpgrade {
+    level: inventory_upgrade_level + 1,
+  };
+  let debits =
+    debit_user_inventory(&mut txn, user_id, &upgrade_cost, strategy, dry_run, &reason).await?;
 
   sqlx::query!(
     "UPDATE bases SET storage_level = storage_level + 1 WHERE user_id = $1",
@@ -74,6 +94,14 @@ pub async fn upgrade_inventory_storage(user_id: i32) -> Result<(), Status> {
     Status::internal("Internal DB error")
   })?;
 
+  if dry_run {
+    txn.rollback().await.map_err(|err| {
+      error!("Failed to roll back transaction: {err}");
+      Status::internal("Internal DB error")
+    })?;
+    return Ok(debits);
+  }
+
   txn.commit().await.map_err(|err| {
     error!("Failed to commit transaction: {err}");
     Status::internal("Internal DB error")
@@ -84,23 +112,51 @@ pub async fn upgrade_inventory_storage(user_id: i32) -> Result<(), Status> {
     inventory_upgrade_level + 1
   );
 
-  Ok(())
+  Ok(debits)
 }
 
-pub(crate) async fn upgrade_base(
+async fn run_upgrade(
   user_id: i32,
-  req: UpgradeBaseRequest,
-) -> Result<Upgrades, Status> {
-  match UpgradeType::try_from(req.upgrade_type) {
-    Ok(UpgradeType::Storage) => upgrade_inventory_storage(user_id).await,
+  upgrade_type: i32,
+  debit_selection: Option<DebitSelection>,
+  dry_run: bool,
+) -> Result<Vec<ItemCostDebit>, Status> {
+  let strategy = DebitStrategy::from_selection(debit_selection)?;
+  match UpgradeType::try_from(upgrade_type) {
+    Ok(UpgradeType::Storage) => upgrade_inventory_storage(user_id, &strategy, dry_run).await,
     Err(_) => {
-      error!("Invalid upgrade type: {}", req.upgrade_type);
-      return Err(Status::invalid_argument("Invalid upgrade type"));
+      error!("Invalid upgrade type: {upgrade_type}");
+      Err(Status::invalid_argument("Invalid upgrade type"))
     },
-  }?;
+  }
+}
 
-  get_user_upgrades(user_id).await.map_err(|err| {
+pub(crate) async fn upgrade_base(
+  user_id: i32,
+  req: UpgradeBaseRequest,
+) -> Result<UpgradeBaseResponse, Status> {
+  let debits = run_upgrade(user_id, req.upgrade_type, req.debit_selection, false).await?;
+
+  let upgrades = get_user_upgrades(user_id).await.map_err(|err| {
     error!("Failed to fetch user upgrades: {err}");
     Status::internal("Internal DB error")
+  })?;
+  let storage_level = upgrades
+    .storage_upgrades
+    .as_ref()
+    .map_or(0, |storage| storage.storage_level);
+  let unlocked_achievements = check_storage_achievements(user_id, storage_level as i32).await;
+
+  Ok(UpgradeBaseResponse {
+    upgrades: Some(upgrades),
+    debits,
+    unlocked_achievements,
   })
 }
+
+pub(crate) async fn preview_upgrade(
+  user_id: i32,
+  req: PreviewUpgradeRequest,
+) -> Result<Vec<ItemCostDebit>, Status> {
+  run_upgrade(user_id, req.upgrade_type, req.debit_selection, true).await
+}
@@ -10,9 +10,15 @@ use foundations::{
 };
 
 use crate::{
+  auth::init_auth,
   conf::Settings,
-  db::init_db,
-  game::{items::init_loot_tables, mine::start_inventory_item_saver},
+  db::{
+    init_db, init_db_pool, rebuild_quality_histograms, recompute_inventory_counts, run_migrations,
+  },
+  game::{
+    achievements::init_achievements, items::init_loot_tables, mine::start_inventory_item_saver,
+    quests::init_quests, stacks::init_quality_histograms,
+  },
   server::start_server,
 };
 
@@ -27,18 +33,43 @@ mod server;
 async fn start() -> BootstrapResult<()> {
   let service_info = foundations::service_info!();
 
-  // Parse command line arguments. Add additional command line option that allows checking
-  // the config without running the server.
-  let cli = Cli::<Settings>::new(&service_info, vec![Arg::new("dry-run")
-    .long("dry-run")
-    .action(ArgAction::SetTrue)
-    .help("Validate or generate config without running the server")])?;
+  // Parse command line arguments. Add additional command line options that allow checking
+  // the config or applying migrations without running the server.
+  let cli = Cli::<Settings>::new(&service_info, vec![
+    Arg::new("dry-run")
+      .long("dry-run")
+      .action(ArgAction::SetTrue)
+      .help("Validate or generate config without running the server"),
+    Arg::new("migrate-only")
+      .long("migrate-only")
+      .action(ArgAction::SetTrue)
+      .help("Apply pending database migrations and exit without running the server"),
+    Arg::new("repair-inventory-counts")
+      .long("repair-inventory-counts")
+      .action(ArgAction::SetTrue)
+      .help("Recompute every user's stored inventory item counts and histograms and exit"),
+  ])?;
 
   // Exit if we just want to check the config.
   if cli.arg_matches.get_flag("dry-run") {
     return Ok(());
   }
 
+  if cli.arg_matches.get_flag("migrate-only") {
+    init_db_pool(&cli.settings).await?;
+    run_migrations().await?;
+    return Ok(());
+  }
+
+  if cli.arg_matches.get_flag("repair-inventory-counts") {
+    init_db_pool(&cli.settings).await?;
+    init_quality_histograms(&cli.settings)?;
+    let repaired = recompute_inventory_counts().await?;
+    info!("Repaired inventory counts for {repaired} users");
+    rebuild_quality_histograms().await?;
+    return Ok(());
+  }
+
   // Initialize telemetry with the settings obtained from the config.
   let tele_serv_fut = telemetry::init_with_server(&service_info, &cli.settings.telemetry, vec![])?;
   if let Some(tele_serv_addr) = tele_serv_fut.server_addr() {
@@ -51,8 +82,12 @@ async fn start() -> BootstrapResult<()> {
   drop(handle);
   info!("Registered tokio runtime metrics");
 
+  init_auth(&cli.settings)?;
+  init_quality_histograms(&cli.settings)?;
   init_db(&cli.settings).await?;
   init_loot_tables()?;
+  init_achievements()?;
+  init_quests()?;
   start_inventory_item_saver().await?;
 
   start_server(&cli.settings).await?;
@@ -11,6 +11,18 @@ pub mod game {
 
 #[metrics]
 pub mod db {
+  /// Connections currently open in the pool, both idle and in use.
+  pub fn pool_connections(pool: &'static str) -> Gauge;
+
+  pub fn pool_idle_connections(pool: &'static str) -> Gauge;
+
+  pub fn pool_max_connections(pool: &'static str) -> Gauge;
+
+  pub fn replica_lag_ms() -> Gauge;
+
+  /// 1 if read queries are currently being routed to the replica, 0 otherwise.
+  pub fn replica_healthy() -> Gauge;
+
   #[ctor = HistogramBuilder {
     buckets: &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 2.5, 5.0, 10.0]
   }]
@@ -12,27 +12,79 @@ use tower_http::{
 use uuid::Uuid;
 
 use crate::{
-  auth::verify_password,
+  auth::{
+    check_guest_creation_throttle, client_ip, create_api_key, verify_password, API_KEY_PREFIX,
+  },
   conf::Settings,
-  db::{insert_session_token, validate_session_token},
+  db::{insert_session_token, validate_api_key, validate_session_token},
   game::{
-    items::{gamble_locations, mine_locations},
+    achievements::achievements,
+    items::{gamble_locations, is_location_unlocked, mine_locations},
     mine::{start_mining, stop_mining, StopMiningReason},
+    prestige::get_prestige_info,
   },
   protos::{
     mine_private_service_server::{MinePrivateService, MinePrivateServiceServer},
     mine_public_service_server::{MinePublicService, MinePublicServiceServer},
-    GambleLocationRes, GetAccountRequest, GetAccountResponse, GetBaseRequest, GetBaseResponse,
+    AggregatedInventory, ApiKeyScope, ClaimGuestAccountRequest, ClaimGuestAccountResponse,
+    CompleteQuestRequest, CompleteQuestResponse, CreateApiKeyRequest, CreateApiKeyResponse,
+    CreateGuestRequest, CreateGuestResponse, EquipRequest, EquipResponse, GambleLocationRes,
+    GetAccountRequest, GetAccountResponse, GetAchievementsRequest, GetAchievementsResponse,
+    GetAggregatedInventoryRequest, GetAggregatedInventoryResponse, GetBaseRequest, GetBaseResponse,
+    GetDailyQuestsRequest, GetDailyQuestsResponse, GetEquipmentRequest, GetEquipmentResponse,
     GetGambleLocationsRequest, GetGambleLocationsResponse, GetHiscoresRequest, GetHiscoresResponse,
+    GetHistoryRequest, GetHistoryResponse, GetInventoryPageRequest, GetInventoryPageResponse,
     GetInventoryRequest, GetInventoryResponse, GetItemDescriptorsRequest, GetMineLocationsRequest,
-    GetMineLocationsResponse, LoginRequest, LoginResponse, MineLocationRes, RegisterRequest,
-    RegisterResponse, SortBy, SortDirection, StartMiningRequest, StartMiningResponse,
-    StopMiningRequest, StopMiningResponse, UpgradeBaseRequest, UpgradeBaseResponse,
+    GetMineLocationsResponse, GetProfileRequest, GetProfileResponse, GetProfileSettingsRequest,
+    GetProfileSettingsResponse, GetStatsRequest, GetStatsResponse, Item, ListApiKeysRequest,
+    ListApiKeysResponse, LoginRequest, LoginResponse, MineLocationRes, PrestigeRequest,
+    PrestigeResponse, PreviewUpgradeRequest, PreviewUpgradeResponse, ProfileSettings,
+    RegisterRequest, RegisterResponse, RevokeApiKeyRequest, RevokeApiKeyResponse,
+    SetItemLockedRequest, SetItemLockedResponse, SetProfileSettingsRequest,
+    SetProfileSettingsResponse, SortBy, SortDirection, StartMiningRequest, StartMiningResponse,
+    StopMiningRequest, StopMiningResponse, UnequipRequest, UnequipResponse, UpgradeBaseRequest,
+    UpgradeBaseResponse,
   },
 };
 
+const MAX_SHOWCASE_ITEMS: usize = 6;
+
 struct MinePrivateServer {}
 
+/// Fetches a page of the user's inventory along with the total number of items in it.
+async fn get_inventory_page(
+  user_id: i32,
+  page_size: u32,
+  page_number: u32,
+  sort_by: i32,
+  sort_direction: i32,
+) -> Result<(Vec<Item>, u32), Status> {
+  let sort_by = SortBy::try_from(sort_by).unwrap_or(SortBy::DateAcquired);
+  let sort_direction = SortDirection::try_from(sort_direction).unwrap_or(SortDirection::Descending);
+  let (items, total_items) = tokio::try_join!(
+    crate::db::get_user_inventory(user_id, page_size, page_number, sort_by, sort_direction)
+      .map_err(|err| {
+        error!("Error reading user inventory from database: {err}");
+        Status::internal("Internal DB error fetching inventory")
+      }),
+    crate::db::get_user_inventory_entry_count(user_id).map_err(|err| {
+      error!("Error counting user inventory items: {err}");
+      Status::internal("Internal DB error fetching inventory")
+    })
+  )?;
+
+  Ok((items, total_items as u32))
+}
+
+async fn get_aggregated_inventory(user_id: i32) -> Result<AggregatedInventory, Status> {
+  crate::db::get_user_aggregated_inventory(user_id)
+    .await
+    .map_err(|err| {
+      error!("Error building aggregated inventory: {err}");
+      Status::internal("Internal DB error building aggregated inventory")
+    })
+}
+
 struct MinePublicServer {}
 
 struct UserCredentials {
@@ -75,7 +127,7 @@ impl MinePrivateService for MinePrivateServer {
         .iter()
         .map(|loc| GambleLocationRes {
           descriptor: Some(loc.descriptor.clone()),
-          is_available: loc.descriptor.id == 0, // TODO
+          is_available: is_location_unlocked(loc),
         })
         .collect(),
     }))
@@ -89,7 +141,7 @@ impl MinePrivateService for MinePrivateServer {
         .iter()
         .map(|loc| MineLocationRes {
           descriptor: Some(loc.descriptor.clone()),
-          is_available: loc.descriptor.id == 0, // TODO
+          is_available: is_location_unlocked(loc),
         })
         .collect(),
     }))
@@ -114,6 +166,154 @@ impl MinePrivateService for MinePrivateServer {
     }
   }
 
+  async fn claim_guest_account(
+    &self,
+    req: Request<ClaimGuestAccountRequest>,
+  ) -> Result<Response<ClaimGuestAccountResponse>, Status> {
+    let user_id = req.user_id();
+    let ClaimGuestAccountRequest { username, password } = req.into_inner();
+
+    let account_info = crate::db::claim_guest_account(user_id, &username, &password).await?;
+
+    info!("User {user_id} claimed guest account as {username}");
+    Ok(Response::new(ClaimGuestAccountResponse {
+      user_account_info: Some(account_info),
+    }))
+  }
+
+  async fn create_api_key(
+    &self,
+    req: Request<CreateApiKeyRequest>,
+  ) -> Result<Response<CreateApiKeyResponse>, Status> {
+    let user_id = req.user_id();
+    let CreateApiKeyRequest { name, scopes } = req.into_inner();
+    let mut scopes = scopes
+      .into_iter()
+      .map(ApiKeyScope::try_from)
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|_| Status::invalid_argument("Invalid API key scope"))?;
+    scopes.sort_unstable();
+    scopes.dedup();
+
+    let (api_key, info) = create_api_key(user_id, &name, &scopes).await?;
+
+    info!("User {user_id} created API key {}", info.id);
+    Ok(Response::new(CreateApiKeyResponse {
+      api_key,
+      info: Some(info),
+    }))
+  }
+
+  async fn list_api_keys(
+    &self,
+    req: Request<ListApiKeysRequest>,
+  ) -> Result<Response<ListApiKeysResponse>, Status> {
+    let user_id = req.user_id();
+    let api_keys = crate::db::get_user_api_keys(user_id).await.map_err(|err| {
+      error!("Error reading API keys from database: {err}");
+      Status::internal("Internal DB error fetching API keys")
+    })?;
+
+    Ok(Response::new(ListApiKeysResponse { api_keys }))
+  }
+
+  async fn revoke_api_key(
+    &self,
+    req: Request<RevokeApiKeyRequest>,
+  ) -> Result<Response<RevokeApiKeyResponse>, Status> {
+    let user_id = req.user_id();
+    let RevokeApiKeyRequest { id } = req.into_inner();
+
+    let revoked = crate::db::revoke_api_key(user_id, id)
+      .await
+      .map_err(|err| {
+        error!("Error revoking API key: {err}");
+        Status::internal("Internal DB error revoking API key")
+      })?;
+    if !revoked {
+      return Err(Status::not_found("API key not found"));
+    }
+
+    info!("User {user_id} revoked API key {id}");
+    Ok(Response::new(RevokeApiKeyResponse {}))
+  }
+
+  async fn get_profile_settings(
+    &self,
+    req: Request<GetProfileSettingsRequest>,
+  ) -> Result<Response<GetProfileSettingsResponse>, Status> {
+    let settings = crate::db::get_profile_settings(req.user_id())
+      .await
+      .map_err(|err| {
+        error!("Error reading profile settings from database: {err}");
+        Status::internal("Internal DB error fetching profile settings")
+      })?;
+
+    Ok(Response::new(GetProfileSettingsResponse {
+      settings: Some(settings),
+    }))
+  }
+
+  async fn set_profile_settings(
+    &self,
+    req: Request<SetProfileSettingsRequest>,
+  ) -> Result<Response<SetProfileSettingsResponse>, Status> {
+    let user_id = req.user_id();
+    let ProfileSettings {
+      hide_inventory,
+      showcase_item_uuids,
+    } = req.into_inner().settings.unwrap_or_default();
+    if showcase_item_uuids.len() > MAX_SHOWCASE_ITEMS {
+      return Err(Status::invalid_argument(format!(
+        "Can't showcase more than {MAX_SHOWCASE_ITEMS} items"
+      )));
+    }
+    let mut showcase_item_ids = Vec::with_capacity(showcase_item_uuids.len());
+    for uuid in &showcase_item_uuids {
+      let id = Uuid::parse_str(uuid)
+        .map_err(|_| Status::invalid_argument(format!("Invalid item UUID: {uuid}")))?;
+      if !showcase_item_ids.contains(&id) {
+        showcase_item_ids.push(id);
+      }
+    }
+
+    let saved = crate::db::set_profile_settings(user_id, hide_inventory, &showcase_item_ids)
+      .await
+      .map_err(|err| {
+        error!("Error saving profile settings: {err}");
+        Status::internal("Internal DB error saving profile settings")
+      })?;
+    if !saved {
+      return Err(Status::not_found(
+        "Showcase items must be in your inventory",
+      ));
+    }
+
+    Ok(Response::new(SetProfileSettingsResponse {}))
+  }
+
+  async fn set_item_locked(
+    &self,
+    req: Request<SetItemLockedRequest>,
+  ) -> Result<Response<SetItemLockedResponse>, Status> {
+    let user_id = req.user_id();
+    let SetItemLockedRequest { item_uuid, locked } = req.into_inner();
+    let item_uuid = Uuid::parse_str(&item_uuid)
+      .map_err(|_| Status::invalid_argument(format!("Invalid item UUID: {item_uuid}")))?;
+
+    let found = crate::db::set_item_locked(user_id, item_uuid, locked)
+      .await
+      .map_err(|err| {
+        error!("Error setting item locked: {err}");
+        Status::internal("Internal DB error setting item locked")
+      })?;
+    if !found {
+      return Err(Status::not_found("Item not found in inventory"));
+    }
+
+    Ok(Response::new(SetItemLockedResponse {}))
+  }
+
   async fn get_base(
     &self,
     req: Request<GetBaseRequest>,
@@ -124,8 +324,16 @@ impl MinePrivateService for MinePrivateServer {
       Status::internal("Internal DB error fetching upgrades")
     })?;
 
+    let prestige_level = crate::db::get_user_prestige_level(user_id)
+      .await
+      .map_err(|err| {
+        error!("Error reading prestige level from database: {err}");
+        Status::internal("Internal DB error fetching prestige level")
+      })?;
+
     Ok(Response::new(GetBaseResponse {
       upgrades: Some(upgrades),
+      prestige: Some(get_prestige_info(prestige_level)),
     }))
   }
 
@@ -141,25 +349,10 @@ impl MinePrivateService for MinePrivateServer {
       sort_direction,
     } = req.into_inner();
 
-    let sort_by = SortBy::try_from(sort_by).unwrap_or(SortBy::DateAcquired);
-    let sort_direction =
-      SortDirection::try_from(sort_direction).unwrap_or(SortDirection::Descending);
-    let (items, aggregated_inventory) = tokio::try_join!(
-      crate::db::get_user_inventory(user_id, page_size, page_number, sort_by, sort_direction)
-        .map_err(|err| {
-          error!("Error reading user inventory from database: {err}");
-          Status::internal("Internal DB error fetching inventory")
-        }),
-      crate::db::get_user_aggregated_inventory(user_id).map_err(|err| {
-        error!("Error building aggregated inventory: {err}");
-        Status::internal("Internal DB error building aggregated inventory")
-      })
+    let ((items, total_items), aggregated_inventory) = tokio::try_join!(
+      get_inventory_page(user_id, page_size, page_number, sort_by, sort_direction),
+      get_aggregated_inventory(user_id)
     )?;
-    let total_items = aggregated_inventory
-      .item_counts
-      .iter()
-      .map(|count| count.total_count)
-      .sum::<u32>();
 
     Ok(Response::new(GetInventoryResponse {
       items,
@@ -168,6 +361,107 @@ impl MinePrivateService for MinePrivateServer {
     }))
   }
 
+  async fn get_inventory_page(
+    &self,
+    req: Request<GetInventoryPageRequest>,
+  ) -> Result<Response<GetInventoryPageResponse>, Status> {
+    let user_id = req.user_id();
+    let GetInventoryPageRequest {
+      page_size,
+      page_number,
+      sort_by,
+      sort_direction,
+    } = req.into_inner();
+
+    let (items, total_items) =
+      get_inventory_page(user_id, page_size, page_number, sort_by, sort_direction).await?;
+
+    Ok(Response::new(GetInventoryPageResponse {
+      items,
+      total_items,
+    }))
+  }
+
+  async fn get_aggregated_inventory(
+    &self,
+    req: Request<GetAggregatedInventoryRequest>,
+  ) -> Result<Response<GetAggregatedInventoryResponse>, Status> {
+    let aggregated_inventory = get_aggregated_inventory(req.user_id()).await?;
+
+    Ok(Response::new(GetAggregatedInventoryResponse {
+      aggregated_inventory: Some(aggregated_inventory),
+    }))
+  }
+
+  async fn get_history(
+    &self,
+    req: Request<GetHistoryRequest>,
+  ) -> Result<Response<GetHistoryResponse>, Status> {
+    let user_id = req.user_id();
+    let GetHistoryRequest {
+      page_size,
+      page_number,
+    } = req.into_inner();
+
+    let entries = crate::db::get_user_history(user_id, page_size, page_number)
+      .await
+      .map_err(|err| {
+        error!("Error reading inventory history from database: {err}");
+        Status::internal("Internal DB error fetching history")
+      })?;
+    Ok(Response::new(GetHistoryResponse { entries }))
+  }
+
+  async fn get_stats(
+    &self,
+    req: Request<GetStatsRequest>,
+  ) -> Result<Response<GetStatsResponse>, Status> {
+    let stats = crate::db::get_user_stats(req.user_id())
+      .await
+      .map_err(|err| {
+        error!("Error reading user stats from database: {err}");
+        Status::internal("Internal DB error fetching stats")
+      })?;
+    Ok(Response::new(GetStatsResponse { stats: Some(stats) }))
+  }
+
+  async fn get_achievements(
+    &self,
+    req: Request<GetAchievementsRequest>,
+  ) -> Result<Response<GetAchievementsResponse>, Status> {
+    let unlocked = crate::db::get_user_achievements(req.user_id())
+      .await
+      .map_err(|err| {
+        error!("Error reading achievements from database: {err}");
+        Status::internal("Internal DB error fetching achievements")
+      })?;
+
+    Ok(Response::new(GetAchievementsResponse {
+      achievements: achievements()
+        .iter()
+        .map(|achievement| achievement.to_proto(unlocked.get(&achievement.id).copied()))
+        .collect(),
+    }))
+  }
+
+  async fn get_daily_quests(
+    &self,
+    req: Request<GetDailyQuestsRequest>,
+  ) -> Result<Response<GetDailyQuestsResponse>, Status> {
+    let res = crate::game::quests::get_daily_quests(req.user_id()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_equipment(
+    &self,
+    req: Request<GetEquipmentRequest>,
+  ) -> Result<Response<GetEquipmentResponse>, Status> {
+    let equipment = crate::game::equipment::get_equipment(req.user_id()).await?;
+    Ok(Response::new(GetEquipmentResponse {
+      equipment: Some(equipment),
+    }))
+  }
+
   // Gameplay
 
   async fn start_mining(
@@ -210,23 +504,72 @@ impl MinePrivateService for MinePrivateServer {
     Ok(Response::new(StopMiningResponse {}))
   }
 
+  async fn equip(&self, req: Request<EquipRequest>) -> Result<Response<EquipResponse>, Status> {
+    let user_id = req.user_id();
+    let EquipRequest { item_uuid } = req.into_inner();
+    let equipment = crate::game::equipment::equip(user_id, &item_uuid).await?;
+    Ok(Response::new(EquipResponse {
+      equipment: Some(equipment),
+    }))
+  }
+
+  async fn unequip(
+    &self,
+    req: Request<UnequipRequest>,
+  ) -> Result<Response<UnequipResponse>, Status> {
+    let user_id = req.user_id();
+    let UnequipRequest { slot } = req.into_inner();
+    let equipment = crate::game::equipment::unequip(user_id, slot).await?;
+    Ok(Response::new(UnequipResponse {
+      equipment: Some(equipment),
+    }))
+  }
+
   async fn upgrade_base(
     &self,
     req: Request<UpgradeBaseRequest>,
   ) -> Result<Response<UpgradeBaseResponse>, Status> {
     let user_id = req.user_id();
-    let upgrades = crate::game::upgrades::upgrade_base(user_id, req.into_inner()).await?;
-    Ok(Response::new(UpgradeBaseResponse {
-      upgrades: Some(upgrades),
+    let res = crate::game::upgrades::upgrade_base(user_id, req.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn preview_upgrade(
+    &self,
+    req: Request<PreviewUpgradeRequest>,
+  ) -> Result<Response<PreviewUpgradeResponse>, Status> {
+    let user_id = req.user_id();
+    let debits = crate::game::upgrades::preview_upgrade(user_id, req.into_inner()).await?;
+    let affordable = debits.iter().all(|debit| debit.missing_quality <= 0.);
+    Ok(Response::new(PreviewUpgradeResponse { debits, affordable }))
+  }
+
+  async fn prestige(
+    &self,
+    req: Request<PrestigeRequest>,
+  ) -> Result<Response<PrestigeResponse>, Status> {
+    let prestige = crate::game::prestige::prestige(req.user_id()).await?;
+    Ok(Response::new(PrestigeResponse {
+      prestige: Some(prestige),
     }))
   }
+
+  async fn complete_quest(
+    &self,
+    req: Request<CompleteQuestRequest>,
+  ) -> Result<Response<CompleteQuestResponse>, Status> {
+    let user_id = req.user_id();
+    let res = crate::game::quests::complete_quest(user_id, req.into_inner()).await?;
+    Ok(Response::new(res))
+  }
 }
 
 #[tonic::async_trait]
 impl MinePublicService for MinePublicServer {
   async fn login(&self, req: Request<LoginRequest>) -> Result<Response<LoginResponse>, Status> {
+    let client_ip = client_ip(&req);
     let LoginRequest { username, password } = req.into_inner();
-    let user_id = verify_password(&username, &password).await?;
+    let user_id = verify_password(&username, &password, client_ip).await?;
 
     let session_token = crate::auth::generate_session_token();
     insert_session_token(user_id, &session_token)
@@ -260,6 +603,30 @@ impl MinePublicService for MinePublicServer {
     Ok(Response::new(RegisterResponse { session_token }))
   }
 
+  async fn create_guest(
+    &self,
+    req: Request<CreateGuestRequest>,
+  ) -> Result<Response<CreateGuestResponse>, Status> {
+    check_guest_creation_throttle(client_ip(&req))?;
+
+    let username = crate::auth::generate_guest_username();
+    let user_id = crate::db::insert_guest_user(&username).await?;
+
+    let session_token = crate::auth::generate_session_token();
+    insert_session_token(user_id, &session_token)
+      .await
+      .map_err(|err| {
+        error!("Error inserting session token: {err}");
+        Status::internal("Internal DB error")
+      })?;
+
+    info!("Created guest user {username}");
+    Ok(Response::new(CreateGuestResponse {
+      session_token,
+      username,
+    }))
+  }
+
   async fn get_hiscores(
     &self,
     _req: Request<GetHiscoresRequest>,
@@ -271,21 +638,67 @@ impl MinePublicService for MinePublicServer {
 
     Ok(Response::new(GetHiscoresResponse { hiscores }))
   }
+
+  async fn get_profile(
+    &self,
+    req: Request<GetProfileRequest>,
+  ) -> Result<Response<GetProfileResponse>, Status> {
+    let GetProfileRequest { username } = req.into_inner();
+    let Some(profile) = crate::db::get_public_profile(&username).await? else {
+      return Err(Status::not_found("Player not found"));
+    };
+
+    Ok(Response::new(GetProfileResponse {
+      profile: Some(profile),
+    }))
+  }
 }
 
 #[derive(Clone)]
 struct AuthInterceptor {
   session_token_lifetime: Duration,
+  guest_session_token_lifetime: Duration,
 }
 
 impl AuthInterceptor {
   fn new(settings: &Settings) -> Self {
     Self {
       session_token_lifetime: Duration::from_secs(settings.auth.session_token_lifetime_seconds),
+      guest_session_token_lifetime: Duration::from_secs(
+        settings.auth.guest_session_token_lifetime_seconds,
+      ),
     }
   }
 }
 
+enum ApiKeyAccess {
+  Any,
+  Scope(ApiKeyScope),
+  Denied,
+}
+
+/// Determines whether an API key can be used to call a `MinePrivateService` method.  Methods that
+/// aren't listed here can only be called with a session token.
+fn api_key_access(method_name: &str) -> ApiKeyAccess {
+  match method_name {
+    "GetItemDescriptors" | "GetMineLocations" | "GetGambleLocations" => ApiKeyAccess::Any,
+    "GetAccount"
+    | "GetInventory"
+    | "GetInventoryPage"
+    | "GetAggregatedInventory"
+    | "GetBase"
+    | "PreviewUpgrade"
+    | "GetHistory"
+    | "GetStats"
+    | "GetAchievements"
+    | "GetDailyQuests"
+    | "GetEquipment" => ApiKeyAccess::Scope(ApiKeyScope::ReadInventory),
+    "StartMining" | "StopMining" | "Equip" | "Unequip" => ApiKeyAccess::Scope(ApiKeyScope::Mining),
+    "UpgradeBase" | "SetItemLocked" | "CompleteQuest" => ApiKeyAccess::Scope(ApiKeyScope::Trading),
+    _ => ApiKeyAccess::Denied,
+  }
+}
+
 #[async_trait::async_trait]
 impl RequestInterceptor for AuthInterceptor {
   async fn intercept(
@@ -299,7 +712,37 @@ impl RequestInterceptor for AuthInterceptor {
       None => return Err(Status::unauthenticated("Missing `authorization` header")),
     };
 
-    let user_id = match validate_session_token(token, self.session_token_lifetime).await? {
+    if token.starts_with(API_KEY_PREFIX) {
+      let api_key = match validate_api_key(token).await? {
+        Some(api_key) => api_key,
+        None => return Err(Status::unauthenticated("Invalid API key")),
+      };
+
+      let method_name = req.uri().path().rsplit('/').next().unwrap_or_default();
+      let allowed = match api_key_access(method_name) {
+        ApiKeyAccess::Any => true,
+        ApiKeyAccess::Scope(scope) => api_key.scopes.contains(&scope),
+        ApiKeyAccess::Denied => false,
+      };
+      if !allowed {
+        return Err(Status::permission_denied(format!(
+          "API key is not allowed to call {method_name}"
+        )));
+      }
+
+      req.extensions_mut().insert(UserCredentials {
+        user_id: api_key.user_id,
+      });
+      return Ok(req);
+    }
+
+    let user_id = match validate_session_token(
+      token,
+      self.session_token_lifetime,
+      self.guest_session_token_lifetime,
+    )
+    .await?
+    {
       Some(user_id) => user_id,
       None => return Err(Status::unauthenticated("Invalid session token")),
     };